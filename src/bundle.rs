@@ -0,0 +1,307 @@
+//! Bundle mode for `open`: finding the files an HTML page needs so it
+//! renders on the Mac as it does on the remote.
+//!
+//! The page is scanned for relative references in `src`, `href`,
+//! `srcset`, `poster` and `data` attributes and in CSS `url(...)`;
+//! stylesheets it references are scanned the same way for their fonts
+//! and images, and `@import`s. This is a scan, not a parser: it may pick
+//! up a reference in a comment or script that the page never loads, which
+//! costs nothing but the transfer. Pages linked from the page are sent
+//! but not scanned.
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+
+/// Cap on the total size of the files sent along with a page; the rest
+/// are left out.
+pub const MAX_ASSETS_SIZE: u64 = 50 << 20;
+
+/// How far above the page's directory a reference may reach
+/// (`../../assets/x.css`). Anything further is left out, so a stray
+/// `../../../..` can't turn the bundle into half the filesystem.
+const MAX_LEVELS_UP: usize = 2;
+
+/// Files larger than this are sent but not scanned.
+const MAX_SCAN_SIZE: u64 = 4 << 20;
+
+/// Where to root a bundle for `page` (canonical), and the files to send
+/// with it: every existing regular file the page references, directly or
+/// through stylesheets, that lies under the root. Empty when the page
+/// references nothing.
+pub fn collect(page: &Path) -> (PathBuf, Vec<PathBuf>) {
+    let page_dir = page.parent().unwrap_or(Path::new("/"));
+    let limit = page_dir.ancestors().nth(MAX_LEVELS_UP).unwrap_or(page_dir);
+    let mut seen: HashSet<PathBuf> = HashSet::from([page.to_path_buf()]);
+    let mut assets = Vec::new();
+    let mut queue = vec![page.to_path_buf()];
+    while let Some(file) = queue.pop() {
+        let Some(text) = read_text(&file) else { continue };
+        let mut refs = css_urls(&text);
+        if !is_css(&file) {
+            refs.extend(attr_urls(&text));
+        }
+        let dir = file.parent().unwrap_or(Path::new("/"));
+        for r in refs {
+            let Some(rel) = relative_target(&r) else { continue };
+            let Ok(target) = std::fs::canonicalize(dir.join(rel)) else {
+                continue;
+            };
+            if !target.starts_with(limit)
+                || !target.is_file()
+                || !seen.insert(target.clone())
+            {
+                continue;
+            }
+            if is_css(&target) {
+                queue.push(target.clone());
+            }
+            assets.push(target);
+        }
+    }
+    let mut root = page_dir.to_path_buf();
+    for asset in &assets {
+        while !asset.starts_with(&root) {
+            if !root.pop() {
+                break;
+            }
+        }
+    }
+    (root, assets)
+}
+
+fn is_css(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e.eq_ignore_ascii_case("css"))
+}
+
+fn read_text(path: &Path) -> Option<String> {
+    let meta = std::fs::metadata(path).ok()?;
+    if meta.len() > MAX_SCAN_SIZE {
+        return None;
+    }
+    let data = std::fs::read(path).ok()?;
+    Some(String::from_utf8_lossy(&data).into_owned())
+}
+
+/// Values of the attributes that load or link a resource.
+fn attr_urls(text: &str) -> Vec<String> {
+    let lower = text.to_ascii_lowercase();
+    let mut urls = Vec::new();
+    for attr in ["src", "href", "poster", "data", "srcset"] {
+        for (at, _) in lower.match_indices(attr) {
+            // A whole attribute name, not the tail of another word.
+            if at == 0 || !lower.as_bytes()[at - 1].is_ascii_whitespace() {
+                continue;
+            }
+            let rest = text[at + attr.len()..].trim_start();
+            let Some(rest) = rest.strip_prefix('=') else { continue };
+            let Some(value) = attr_value(rest.trim_start()) else {
+                continue;
+            };
+            if attr == "srcset" {
+                // `a.png 1x, b.png 2x`
+                urls.extend(
+                    value
+                        .split(',')
+                        .filter_map(|c| c.split_whitespace().next())
+                        .map(|s| s.to_string()),
+                );
+            } else {
+                urls.push(value.to_string());
+            }
+        }
+    }
+    urls
+}
+
+/// A quoted or bare attribute value at the start of `s`.
+fn attr_value(s: &str) -> Option<&str> {
+    match s.chars().next()? {
+        q @ ('"' | '\'') => s[1..].split(q).next(),
+        _ => s
+            .split(|c: char| c.is_ascii_whitespace() || c == '>')
+            .next()
+            .filter(|v| !v.is_empty()),
+    }
+}
+
+/// `url(...)` targets and `@import "..."` strings.
+fn css_urls(text: &str) -> Vec<String> {
+    let lower = text.to_ascii_lowercase();
+    let mut urls = Vec::new();
+    for (at, _) in lower.match_indices("url(") {
+        let rest = text[at + 4..].trim_start();
+        let value = match rest.chars().next() {
+            Some(q @ ('"' | '\'')) => rest[1..].split(q).next(),
+            _ => rest.split(')').next(),
+        };
+        if let Some(v) = value {
+            urls.push(v.trim().to_string());
+        }
+    }
+    for (at, _) in lower.match_indices("@import") {
+        let rest = text[at + 7..].trim_start();
+        if let Some(q @ ('"' | '\'')) = rest.chars().next() {
+            if let Some(v) = rest[1..].split(q).next() {
+                urls.push(v.to_string());
+            }
+        }
+    }
+    urls
+}
+
+/// The local path a reference names, if it is a relative one: no scheme,
+/// no leading `/`, and not just a fragment. The query and fragment are
+/// dropped and `%XX` escapes decoded.
+fn relative_target(reference: &str) -> Option<PathBuf> {
+    let r = reference.trim().replace("&amp;", "&");
+    let r = r.split(['#', '?']).next().unwrap_or("");
+    if r.is_empty() || r.starts_with('/') || r.starts_with('\\') {
+        return None;
+    }
+    if let Some(colon) = r.find(':') {
+        let scheme = &r[..colon];
+        if !scheme.contains('/') {
+            return None;
+        }
+    }
+    // `%2f` decodes to a separator, so check again.
+    let path = PathBuf::from(OsString::from_vec(percent_decode(r)));
+    (!path.has_root()).then_some(path)
+}
+
+fn percent_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_target_takes_relative_paths() {
+        assert_eq!(relative_target("a.css"), Some(PathBuf::from("a.css")));
+        assert_eq!(
+            relative_target(" img/a.png?v=2#top "),
+            Some(PathBuf::from("img/a.png"))
+        );
+        assert_eq!(
+            relative_target("../x.css"),
+            Some(PathBuf::from("../x.css"))
+        );
+        // A colon after a slash is part of a path, not a scheme.
+        assert_eq!(
+            relative_target("dir/a:b.png"),
+            Some(PathBuf::from("dir/a:b.png"))
+        );
+    }
+
+    #[test]
+    fn relative_target_skips_schemes_and_absolute_paths() {
+        for r in [
+            "https://example.com/a.css",
+            "data:image/png;base64,AAAA",
+            "javascript:void(0)",
+            "mailto:me@example.com",
+            "a:b.png",
+            "/etc/passwd",
+            "\\share\\a.png",
+            "#top",
+            "?q=1",
+            "",
+        ] {
+            assert_eq!(relative_target(r), None, "{:?}", r);
+        }
+    }
+
+    #[test]
+    fn relative_target_decodes_escapes() {
+        assert_eq!(
+            relative_target("my%20file.png"),
+            Some(PathBuf::from("my file.png"))
+        );
+        // Dot segments hidden in escapes are still dot segments; `collect`
+        // bounds how far up they reach.
+        assert_eq!(
+            relative_target("%2e%2e/%2E%2E/x.css"),
+            Some(PathBuf::from("../../x.css"))
+        );
+        assert_eq!(
+            relative_target("a%2fb.png"),
+            Some(PathBuf::from("a/b.png"))
+        );
+        assert_eq!(relative_target("%2fetc%2fpasswd"), None);
+        assert_eq!(relative_target("%2F%2Fhost/x"), None);
+    }
+
+    #[test]
+    fn percent_decode_keeps_malformed_escapes() {
+        assert_eq!(percent_decode("a%41b"), b"aAb");
+        assert_eq!(percent_decode("caf%C3%A9"), "café".as_bytes());
+        assert_eq!(percent_decode("%ff"), b"\xff");
+        assert_eq!(percent_decode("100%"), b"100%");
+        assert_eq!(percent_decode("%4"), b"%4");
+        assert_eq!(percent_decode("%zz%2"), b"%zz%2");
+    }
+
+    #[test]
+    fn attr_urls_finds_resource_attributes() {
+        let html = r#"<img src="a.png" srcset="b.png 1x, c.png 2x">
+            <a href='d.html'>d</a> <video poster=e.jpg>
+            <object data="f.svg"></object>"#;
+        assert_eq!(
+            attr_urls(html),
+            ["a.png", "d.html", "e.jpg", "f.svg", "b.png", "c.png"]
+        );
+    }
+
+    #[test]
+    fn attr_urls_needs_whole_attribute_names() {
+        assert!(attr_urls(r#"<div data-src="x.png" xhref="y">"#).is_empty());
+        assert!(attr_urls("src=\"x.png\" at the start").is_empty());
+        assert_eq!(attr_urls(r#"<IMG SRC = "x.png">"#), ["x.png"]);
+    }
+
+    #[test]
+    fn collect_stops_max_levels_up() {
+        let base = std::env::temp_dir()
+            .join(format!("clipcast-bundle-{}", std::process::id()));
+        let page_dir = base.join("a/b/c");
+        std::fs::create_dir_all(&page_dir).unwrap();
+        std::fs::write(base.join("a/two.css"), "").unwrap();
+        std::fs::write(base.join("three.css"), "").unwrap();
+        std::fs::write(
+            page_dir.join("page.html"),
+            r#"<link href="../../two.css"><link href="../../../three.css">
+               <link href="%2e%2e/%2e%2e/%2e%2e/three.css">"#,
+        )
+        .unwrap();
+        let page = std::fs::canonicalize(page_dir.join("page.html")).unwrap();
+        let (root, assets) = collect(&page);
+        let canonical = std::fs::canonicalize(&base).unwrap();
+        let _ = std::fs::remove_dir_all(&base);
+        assert_eq!(assets, [canonical.join("a/two.css")]);
+        assert_eq!(root, canonical.join("a"));
+    }
+}
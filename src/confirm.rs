@@ -0,0 +1,328 @@
+//! Asking the user before opening files the allowlist or policy refuses,
+//! or whose extension a host has never sent before.
+//!
+//! `clipcast client --open-confirm-cmd <cmd>` runs `<cmd>` with the
+//! request described in JSON on stdin and in `CLIPCAST_HOST` /
+//! `CLIPCAST_FILES`. The first word it prints decides: `allow-once`,
+//! `allow-always` (the extensions are added to the handler's `allowlist`
+//! in `--open-handlers`), or `deny`. Anything else, a failure, or no
+//! answer within `--open-confirm-timeout` is a deny. Extensions the user
+//! allowed are remembered per host in `--open-confirm-store`, so each is
+//! asked about once. Executables and app bundles are never offered.
+
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+type BoxError = Box<dyn std::error::Error>;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Decision {
+    AllowOnce,
+    AllowAlways,
+    Deny,
+}
+
+/// One refused file or directory, as shown to the prompt.
+#[derive(Serialize, Debug)]
+pub struct Candidate {
+    pub name: String,
+    pub size: u64,
+    pub is_dir: bool,
+}
+
+#[derive(Serialize)]
+struct PromptInput<'a> {
+    host: &'a str,
+    handler: &'a str,
+    files: &'a [Candidate],
+}
+
+/// Directory extensions macOS runs as code (app bundles and plug-ins).
+const BUNDLE_EXTS: &[&str] = &[
+    "app",
+    "appex",
+    "bundle",
+    "framework",
+    "kext",
+    "plugin",
+    "prefpane",
+    "qlgenerator",
+    "saver",
+    "xpc",
+];
+
+/// File extensions that run or install something when opened.
+const EXEC_EXTS: &[&str] = &["command", "mpkg", "pkg", "terminal", "tool"];
+
+/// Whether a file may be put to the prompt at all. Extension-less files,
+/// executables and app bundles are not, whatever the user would answer.
+pub fn may_ask(name: &str, is_dir: bool, path: &Path) -> bool {
+    let ext = Path::new(name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+    if is_dir {
+        return !ext.is_some_and(|e| BUNDLE_EXTS.contains(&e.as_str()));
+    }
+    let executable = std::fs::metadata(path)
+        .is_ok_and(|m| m.permissions().mode() & 0o111 != 0);
+    ext.is_some_and(|e| !EXEC_EXTS.contains(&e.as_str())) && !executable
+}
+
+#[derive(Debug)]
+pub struct Confirmer {
+    cmd: String,
+    timeout: Duration,
+    host: String,
+    store: PathBuf,
+    /// Extensions already allowed from `host`, as persisted in `store`.
+    seen: Mutex<HashSet<String>>,
+    /// The `--open-handlers` file `allow-always` adds to; `None` without
+    /// one.
+    handlers_file: Option<PathBuf>,
+    /// `--open-allowlist`, for handlers the file gives none.
+    default_allowlist: String,
+    /// `--edit-allowlist`, likewise for the `edit` handler.
+    edit_allowlist: String,
+    /// Extensions added by handler since the handlers were loaded.
+    always: Mutex<HashMap<String, HashSet<String>>>,
+}
+
+impl Confirmer {
+    pub fn new(
+        cmd: String,
+        timeout: Duration,
+        host: String,
+        store: PathBuf,
+        handlers_file: Option<PathBuf>,
+        default_allowlist: String,
+        edit_allowlist: String,
+    ) -> Result<Self, BoxError> {
+        let seen = load_store(&store)?.remove(&host).unwrap_or_default();
+        Ok(Confirmer {
+            cmd,
+            timeout,
+            host,
+            store,
+            seen: Mutex::new(seen.into_iter().collect()),
+            handlers_file,
+            default_allowlist,
+            edit_allowlist,
+            always: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Whether `ext` was allowed always for `handler` since the handlers
+    /// were loaded; earlier answers are in its allowlist.
+    pub fn is_always_allowed(&self, handler: &str, ext: &str) -> bool {
+        self.always
+            .lock()
+            .unwrap()
+            .get(handler)
+            .is_some_and(|exts| exts.contains(ext))
+    }
+
+    /// Whether this host sent a file with `ext` that was allowed before.
+    pub fn has_seen(&self, ext: &str) -> bool {
+        self.seen.lock().unwrap().contains(ext)
+    }
+
+    /// Runs the prompt command and returns the user's answer.
+    pub async fn ask(&self, handler: &str, files: &[Candidate]) -> Decision {
+        match self.run_prompt(handler, files).await {
+            Ok(decision) => decision,
+            Err(e) => {
+                warn!("confirmation prompt failed, denying: {}", e);
+                Decision::Deny
+            }
+        }
+    }
+
+    async fn run_prompt(
+        &self,
+        handler: &str,
+        files: &[Candidate],
+    ) -> Result<Decision, BoxError> {
+        let parts = shlex::split(&self.cmd)
+            .filter(|p| !p.is_empty())
+            .ok_or_else(|| format!("invalid confirm command {:?}", self.cmd))?;
+        let input = serde_json::to_string(&PromptInput {
+            host: &self.host,
+            handler,
+            files,
+        })?;
+        let listing = files
+            .iter()
+            .map(|f| {
+                let kind = if f.is_dir { ", directory" } else { "" };
+                format!(
+                    "{} ({}{})",
+                    f.name,
+                    crate::gc::format_size(f.size),
+                    kind
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut child = Command::new(&parts[0])
+            .args(&parts[1..])
+            .env("CLIPCAST_HOST", &self.host)
+            .env("CLIPCAST_FILES", listing)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // A prompt that doesn't read its input is fine.
+            let _ = stdin.write_all(input.as_bytes()).await;
+        }
+        let output =
+            match tokio::time::timeout(self.timeout, child.wait_with_output())
+                .await
+            {
+                Ok(out) => out?,
+                Err(_) => {
+                    info!("confirmation timed out after {:?}", self.timeout);
+                    return Ok(Decision::Deny);
+                }
+            };
+        if !output.status.success() {
+            return Err(format!("exited with {}", output.status).into());
+        }
+        let answer = String::from_utf8_lossy(&output.stdout);
+        let decision = match answer.split_whitespace().next() {
+            Some("allow-once") => Decision::AllowOnce,
+            Some("allow-always") => Decision::AllowAlways,
+            _ => Decision::Deny,
+        };
+        info!("confirmation for {} file(s): {:?}", files.len(), decision);
+        Ok(decision)
+    }
+
+    /// Records `exts` as allowed from this host and saves the list.
+    pub fn mark_seen(&self, exts: impl IntoIterator<Item = String>) {
+        let snapshot: Vec<String> = {
+            let mut seen = self.seen.lock().unwrap();
+            let before = seen.len();
+            seen.extend(exts);
+            if seen.len() == before {
+                return;
+            }
+            let mut v: Vec<String> = seen.iter().cloned().collect();
+            v.sort();
+            v
+        };
+        if let Err(e) = self.save(snapshot) {
+            warn!("saving {}: {}", self.store.display(), e);
+        }
+    }
+
+    /// Adds `exts` to the allowlist of `handler` (running `cmd`, if it
+    /// has no entry yet) in the `--open-handlers` file. Without a file
+    /// they are allowed this once only.
+    pub fn allow_always(
+        &self,
+        handler: &str,
+        cmd: Option<&str>,
+        exts: &[String],
+    ) {
+        let Some(file) = &self.handlers_file else {
+            warn!("allow-always needs --open-handlers; allowed once");
+            return;
+        };
+        if let Err(e) = self.add_to_allowlist(file, handler, cmd, exts) {
+            warn!("saving {}: {}", file.display(), e);
+            return;
+        }
+        info!("added {:?} to the {:?} allowlist", exts, handler);
+        self.always
+            .lock()
+            .unwrap()
+            .entry(handler.to_string())
+            .or_default()
+            .extend(exts.iter().cloned());
+    }
+
+    fn add_to_allowlist(
+        &self,
+        file: &Path,
+        handler: &str,
+        cmd: Option<&str>,
+        exts: &[String],
+    ) -> Result<(), BoxError> {
+        let mut all: Map<String, Value> = match std::fs::read_to_string(file) {
+            Ok(text) => serde_json::from_str(&text)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Map::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let entry = all.entry(handler).or_insert_with(|| {
+            let mut entry = Map::new();
+            if let Some(cmd) = cmd {
+                entry.insert("cmd".into(), cmd.into());
+            }
+            Value::Object(entry)
+        });
+        let entry = entry
+            .as_object_mut()
+            .ok_or_else(|| format!("handler {:?} is not an object", handler))?;
+        let default = if handler == "edit" {
+            &self.edit_allowlist
+        } else {
+            &self.default_allowlist
+        };
+        let mut list: Vec<String> = entry
+            .get("allowlist")
+            .and_then(Value::as_str)
+            .unwrap_or(default)
+            .split(',')
+            .map(|e| e.trim().to_string())
+            .filter(|e| !e.is_empty())
+            .collect();
+        for ext in exts {
+            if !list.contains(ext) {
+                list.push(ext.clone());
+            }
+        }
+        entry.insert("allowlist".into(), list.join(",").into());
+        let tmp = file.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(&all)?)?;
+        std::fs::rename(&tmp, file)?;
+        Ok(())
+    }
+
+    fn save(&self, exts: Vec<String>) -> Result<(), BoxError> {
+        let mut all = load_store(&self.store)?;
+        all.insert(self.host.clone(), exts);
+        if let Some(parent) = self.store.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = self.store.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(&all)?)?;
+        std::fs::rename(&tmp, &self.store)?;
+        Ok(())
+    }
+}
+
+/// Host → extensions allowed before. A missing file is empty.
+fn load_store(
+    path: &PathBuf,
+) -> Result<HashMap<String, Vec<String>>, BoxError> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => Ok(serde_json::from_str(&text)
+            .map_err(|e| format!("{}: {}", path.display(), e))?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Ok(HashMap::new())
+        }
+        Err(e) => Err(format!("{}: {}", path.display(), e).into()),
+    }
+}
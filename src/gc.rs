@@ -0,0 +1,543 @@
+//! Retention for files received by `open` — `clipcast gc` and the
+//! client's periodic sweep.
+//!
+//! Every open request lands in its own
+//! `<open_base_dir>/<host>/<secs>-<rand>/` directory. Nothing else ever
+//! removes them, so this module applies three per-host policies to those
+//! directories: a maximum age, a maximum total size and a maximum number
+//! of requests. Only directories whose name matches the request-dir
+//! pattern are considered; anything else under `open_base_dir` is left
+//! alone.
+//!
+//! Files kept by `--open-mirror` (`<open_base_dir>/<host>/mirror/`) count
+//! as one request each, created when they were last replaced.
+//!
+//! Each host's content cache (`<open_base_dir>/<host>/.cache/sha256/`)
+//! gets the age and size policies as a whole, by last use rather than by
+//! request.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::Args;
+use tracing::{info, warn};
+
+type BoxError = Box<dyn std::error::Error>;
+
+/// Request directories younger than this are never removed by the size
+/// or count policies: the local app may still be reading the file.
+const MIN_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// How often the client re-applies the policies while connected.
+pub const GC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Args, Debug, Clone)]
+pub struct RetentionArgs {
+    /// Delete request directories older than this (`90m`, `12h`, `30d`).
+    /// `0` disables the age limit.
+    #[arg(long, default_value = "30d")]
+    retention_max_age: String,
+
+    /// Per-host cap on the total size of request directories (`500M`,
+    /// `2G`). Oldest requests go first. `0` disables the size limit.
+    #[arg(long, default_value = "2G")]
+    retention_max_bytes: String,
+
+    /// Per-host cap on the number of request directories kept. `0`
+    /// disables the count limit.
+    #[arg(long, default_value_t = 500)]
+    retention_max_requests: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetentionPolicy {
+    max_age: Option<Duration>,
+    max_bytes: Option<u64>,
+    max_requests: Option<usize>,
+}
+
+impl RetentionArgs {
+    pub fn policy(&self) -> Result<RetentionPolicy, BoxError> {
+        let max_age = parse_duration(&self.retention_max_age).map_err(|e| {
+            format!("--retention-max-age {:?}: {}", self.retention_max_age, e)
+        })?;
+        let max_bytes = parse_size(&self.retention_max_bytes).map_err(|e| {
+            format!(
+                "--retention-max-bytes {:?}: {}",
+                self.retention_max_bytes, e
+            )
+        })?;
+        Ok(RetentionPolicy {
+            max_age: (!max_age.is_zero()).then_some(max_age),
+            max_bytes: (max_bytes > 0).then_some(max_bytes),
+            max_requests: (self.retention_max_requests > 0)
+                .then_some(self.retention_max_requests),
+        })
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct GcCmd {
+    /// Directory under which remote-synced files are stored. Supports
+    /// `~/` prefix.
+    #[arg(long, default_value = "~/.clipcast/remote")]
+    open_base_dir: String,
+
+    #[command(flatten)]
+    retention: RetentionArgs,
+
+    /// Report what would be deleted without deleting anything.
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+}
+
+/// A request directory selected for deletion.
+#[derive(Debug)]
+pub struct Victim {
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub reason: String,
+}
+
+pub fn run(cmd: GcCmd) -> Result<(), BoxError> {
+    let policy = cmd.retention.policy()?;
+    let base_dir = crate::expand_home(&cmd.open_base_dir);
+    let victims = collect(&base_dir, &policy)?;
+    if victims.is_empty() {
+        println!("nothing to delete under {}", base_dir.display());
+        return Ok(());
+    }
+    let total: u64 = victims.iter().map(|v| v.bytes).sum();
+    for v in &victims {
+        let verb = if cmd.dry_run { "would delete" } else { "deleting" };
+        println!(
+            "{} {} ({}, {})",
+            verb,
+            v.path.display(),
+            format_size(v.bytes),
+            v.reason
+        );
+        if !cmd.dry_run {
+            if let Err(e) = remove(&v.path) {
+                eprintln!("failed to delete {}: {}", v.path.display(), e);
+            }
+        }
+    }
+    println!(
+        "{} {} entries, {}",
+        if cmd.dry_run { "would free" } else { "freed" },
+        victims.len(),
+        format_size(total)
+    );
+    Ok(())
+}
+
+/// One sweep for the client: select and delete, logging instead of
+/// printing. Blocking; call it from `spawn_blocking`.
+pub fn sweep(base_dir: &Path, policy: &RetentionPolicy) {
+    let victims = match collect(base_dir, policy) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("retention sweep of {} failed: {}", base_dir.display(), e);
+            return;
+        }
+    };
+    for v in victims {
+        match remove(&v.path) {
+            Ok(()) => info!(
+                "retention: deleted {} ({}, {})",
+                v.path.display(),
+                format_size(v.bytes),
+                v.reason
+            ),
+            Err(e) => {
+                warn!("retention: deleting {}: {}", v.path.display(), e)
+            }
+        }
+    }
+}
+
+fn remove(path: &Path) -> std::io::Result<()> {
+    if path.symlink_metadata()?.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+}
+
+/// Applies `policy` to every host directory under `base_dir` and to its
+/// content cache, and returns what should go, oldest first per host.
+pub fn collect(
+    base_dir: &Path,
+    policy: &RetentionPolicy,
+) -> Result<Vec<Victim>, BoxError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut victims = Vec::new();
+    let hosts = match std::fs::read_dir(base_dir) {
+        Ok(rd) => rd,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(victims)
+        }
+        Err(e) => return Err(format!("{}: {}", base_dir.display(), e).into()),
+    };
+    for host in hosts {
+        let host = host?;
+        // Dot directories (the cache) are never host directories.
+        if !host.file_type()?.is_dir()
+            || host.file_name().to_string_lossy().starts_with('.')
+        {
+            continue;
+        }
+        victims.extend(collect_host(&host.path(), policy, now)?);
+        victims.extend(collect_cache(
+            &crate::content_cache_dir(&host.path()),
+            policy,
+            now,
+        )?);
+    }
+    // The cache used to be shared by all hosts; drain what is left of it.
+    victims.extend(collect_cache(
+        &crate::content_cache_dir(base_dir),
+        policy,
+        now,
+    )?);
+    Ok(victims)
+}
+
+/// Cache entries past the age limit or beyond the size cap, least
+/// recently used first. Hits refresh an entry's mtime. Nothing here is
+/// opened directly, so `MIN_AGE` does not apply.
+fn collect_cache(
+    cache_dir: &Path,
+    policy: &RetentionPolicy,
+    now: u64,
+) -> Result<Vec<Victim>, BoxError> {
+    let rd = match std::fs::read_dir(cache_dir) {
+        Ok(rd) => rd,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Vec::new())
+        }
+        Err(e) => return Err(format!("{}: {}", cache_dir.display(), e).into()),
+    };
+    // (last used secs, bytes, path)
+    let mut entries: Vec<(u64, u64, PathBuf)> = Vec::new();
+    for entry in rd {
+        let entry = entry?;
+        let meta = entry.path().symlink_metadata()?;
+        if !meta.is_file() {
+            continue;
+        }
+        let used = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        entries.push((used, meta.len(), entry.path()));
+    }
+    entries.sort_by_key(|e| std::cmp::Reverse(e.0));
+
+    let mut victims = Vec::new();
+    let mut kept_bytes: u64 = 0;
+    for (used, bytes, path) in entries {
+        let age = Duration::from_secs(now.saturating_sub(used));
+        let reason = if policy.max_age.is_some_and(|max| age > max) {
+            Some(format!(
+                "cache entry unused for {}",
+                format_duration(policy.max_age)
+            ))
+        } else if policy
+            .max_bytes
+            .is_some_and(|max| kept_bytes.saturating_add(bytes) > max)
+        {
+            Some(format!(
+                "over the {} cache cap",
+                format_size(policy.max_bytes.unwrap_or(0))
+            ))
+        } else {
+            None
+        };
+        match reason {
+            Some(reason) => victims.push(Victim { path, bytes, reason }),
+            None => kept_bytes = kept_bytes.saturating_add(bytes),
+        }
+    }
+    victims.reverse();
+    Ok(victims)
+}
+
+fn collect_host(
+    host_dir: &Path,
+    policy: &RetentionPolicy,
+    now: u64,
+) -> Result<Vec<Victim>, BoxError> {
+    // (created secs, path)
+    let mut requests: Vec<(u64, PathBuf)> = Vec::new();
+    for entry in std::fs::read_dir(host_dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let name = entry.file_name();
+        if let Some(secs) = name.to_str().and_then(request_dir_secs) {
+            requests.push((secs, entry.path()));
+        }
+    }
+    requests.extend(mirror_files(&crate::mirror_dir(host_dir)));
+    // Newest first: the count and size budgets are spent on recent
+    // requests.
+    requests.sort_by_key(|r| std::cmp::Reverse(r.0));
+
+    let mut victims = Vec::new();
+    let mut kept_bytes: u64 = 0;
+    let mut kept: usize = 0;
+    for (secs, path) in requests {
+        let age = Duration::from_secs(now.saturating_sub(secs));
+        let bytes = dir_size(&path);
+        let reason = if policy.max_age.is_some_and(|max| age > max) {
+            Some(format!("older than {}", format_duration(policy.max_age)))
+        } else if age < MIN_AGE {
+            None
+        } else if policy.max_requests.is_some_and(|max| kept >= max) {
+            Some(format!(
+                "beyond the newest {} requests",
+                policy.max_requests.unwrap_or(0)
+            ))
+        } else if policy
+            .max_bytes
+            .is_some_and(|max| kept_bytes.saturating_add(bytes) > max)
+        {
+            Some(format!(
+                "over the {} per-host cap",
+                format_size(policy.max_bytes.unwrap_or(0))
+            ))
+        } else {
+            None
+        };
+        match reason {
+            Some(reason) => victims.push(Victim { path, bytes, reason }),
+            None => {
+                kept += 1;
+                kept_bytes = kept_bytes.saturating_add(bytes);
+            }
+        }
+    }
+    victims.reverse();
+    Ok(victims)
+}
+
+/// Creation time encoded in a `<secs>-<rand>` request directory name, or
+/// `None` if the name doesn't have that shape.
+fn request_dir_secs(name: &str) -> Option<u64> {
+    let (secs, suffix) = name.split_once('-')?;
+    if suffix.len() != 6
+        || !suffix.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+    {
+        return None;
+    }
+    if secs.is_empty() || !secs.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    secs.parse().ok()
+}
+
+/// Regular files under the mirror directory `path`, each with when it
+/// was last renamed into place (its ctime; the mtime is the sender's).
+fn mirror_files(path: &Path) -> Vec<(u64, PathBuf)> {
+    let mut files = Vec::new();
+    let mut stack = vec![path.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        let Ok(rd) = std::fs::read_dir(&dir) else { continue };
+        for entry in rd.flatten() {
+            let Ok(meta) = entry.path().symlink_metadata() else { continue };
+            if meta.is_dir() {
+                stack.push(entry.path());
+            } else if meta.is_file() {
+                files.push((meta.ctime().max(0) as u64, entry.path()));
+            }
+        }
+    }
+    files
+}
+
+/// Total size of regular files at or under `path`. Symlinks are not
+/// followed.
+fn dir_size(path: &Path) -> u64 {
+    if let Ok(meta) = path.symlink_metadata() {
+        if meta.is_file() {
+            return meta.len();
+        }
+    }
+    let mut total = 0;
+    let mut stack = vec![path.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        let Ok(rd) = std::fs::read_dir(&dir) else { continue };
+        for entry in rd.flatten() {
+            let Ok(meta) = entry.path().symlink_metadata() else { continue };
+            if meta.is_dir() {
+                stack.push(entry.path());
+            } else if meta.is_file() {
+                total += meta.len();
+            }
+        }
+    }
+    total
+}
+
+/// Parses `90s`, `15m`, `12h`, `30d` or a bare number of seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (num, mult) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        Some((i, 'd')) => (&s[..i], 24 * 60 * 60),
+        _ => (s, 1),
+    };
+    let n: u64 = num
+        .trim()
+        .parse()
+        .map_err(|_| "expected a number with an s/m/h/d suffix".to_string())?;
+    Ok(Duration::from_secs(n.saturating_mul(mult)))
+}
+
+/// Parses `4096`, `512K`, `500M`, `2G` (binary multiples; a trailing `B`
+/// or `iB` is accepted).
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let upper = s.trim().to_ascii_uppercase();
+    let trimmed = upper
+        .strip_suffix("IB")
+        .or_else(|| upper.strip_suffix('B'))
+        .unwrap_or(&upper);
+    let (num, mult) = match trimmed.char_indices().last() {
+        Some((i, 'K')) => (&trimmed[..i], 1u64 << 10),
+        Some((i, 'M')) => (&trimmed[..i], 1 << 20),
+        Some((i, 'G')) => (&trimmed[..i], 1 << 30),
+        Some((i, 'T')) => (&trimmed[..i], 1 << 40),
+        _ => (trimmed, 1),
+    };
+    let n: u64 = num
+        .trim()
+        .parse()
+        .map_err(|_| "expected a size like 500M or 2G".to_string())?;
+    Ok(n.saturating_mul(mult))
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut v = bytes as f64;
+    let mut unit = 0;
+    while v >= 1024.0 && unit < UNITS.len() - 1 {
+        v /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", v, UNITS[unit])
+    }
+}
+
+fn format_duration(d: Option<Duration>) -> String {
+    let secs = d.map(|d| d.as_secs()).unwrap_or(0);
+    if secs % (24 * 60 * 60) == 0 {
+        format!("{}d", secs / (24 * 60 * 60))
+    } else if secs % (60 * 60) == 0 {
+        format!("{}h", secs / (60 * 60))
+    } else if secs % 60 == 0 {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration(" 2h "), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(604_800)));
+        assert_eq!(parse_duration("0d"), Ok(Duration::ZERO));
+        assert_eq!(
+            parse_duration(&format!("{}d", u64::MAX)),
+            Ok(Duration::from_secs(u64::MAX))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_durations() {
+        for s in ["", "d", "-5m", "1.5h", "5w", "m5", "5 days"] {
+            assert!(parse_duration(s).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512K"), Ok(512 << 10));
+        assert_eq!(parse_size("500m"), Ok(500 << 20));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert_eq!(parse_size("1T"), Ok(1 << 40));
+        assert_eq!(parse_size("10B"), Ok(10));
+        assert_eq!(parse_size("500MB"), Ok(500 << 20));
+        assert_eq!(parse_size("1KiB"), Ok(1024));
+        assert_eq!(parse_size(&format!("{}T", u64::MAX)), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn rejects_bad_sizes() {
+        for s in ["", "B", "K", "-1M", "1.5G", "12X", "G2"] {
+            assert!(parse_size(s).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn recognizes_request_dirs() {
+        assert_eq!(request_dir_secs("1792336011-vilsgu"), Some(1792336011));
+        assert_eq!(request_dir_secs("0-a1b2c3"), Some(0));
+    }
+
+    #[test]
+    fn leaves_other_names_alone() {
+        for name in [
+            "1792336011",
+            "1792336011-",
+            "-vilsgu",
+            "1792336011-vilsg",
+            "1792336011-vilsgux",
+            "1792336011-VILSGU",
+            "1792336011-vil-gu",
+            "17923x6011-vilsgu",
+            "+1792336-vilsgu",
+            ".cache",
+            "99999999999999999999999-vilsgu",
+        ] {
+            assert_eq!(request_dir_secs(name), None, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn finds_mirror_files() {
+        let base = std::env::temp_dir()
+            .join(format!("clipcast-gc-mirror-{}", std::process::id()));
+        let mirror = crate::mirror_dir(&base);
+        std::fs::create_dir_all(mirror.join("home/me")).unwrap();
+        std::fs::write(mirror.join("home/me/report.pdf"), "12345").unwrap();
+        std::os::unix::fs::symlink("report.pdf", mirror.join("home/me/link"))
+            .unwrap();
+        let files = mirror_files(&mirror);
+        assert_eq!(files.len(), 1, "{:?}", files);
+        assert_eq!(files[0].1, mirror.join("home/me/report.pdf"));
+        assert_eq!(dir_size(&files[0].1), 5);
+        let _ = std::fs::remove_dir_all(&base);
+    }
+}
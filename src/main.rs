@@ -7,34 +7,57 @@
 //! rand = "0.8"
 //! serde = { version = "1.0.215", features = ["derive"] }
 //! serde_json = "1.0.133"
+//! sha2 = "0.10"
 //! shlex = "1.3.0"
 //! tokio = { version = "1.42.0", features = ["full"] }
 //! tracing = "0.1.41"
 //! tracing-subscriber = { version = "0.3.19", features = ["env-filter"] }
 //! ```
+mod bundle;
+mod confirm;
 mod deploy;
+mod gc;
+mod policy;
 
 use base64::engine::general_purpose::STANDARD as B64;
 use base64::Engine as _;
 use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use confirm::{Confirmer, Decision};
+use policy::{OpenPolicy, Verdict};
 use rand::distributions::{Alphanumeric, DistString};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use std::ffi::OsString;
-use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::io::IsTerminal;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{
+    DirBuilderExt,
+    MetadataExt,
+    OpenOptionsExt,
+    PermissionsExt,
+};
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::fs as tfs;
 use tokio::io::{
-    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+    AsyncBufRead,
+    AsyncBufReadExt,
+    AsyncReadExt,
+    AsyncSeekExt,
+    AsyncWrite,
+    AsyncWriteExt,
     BufReader,
 };
 use tokio::net::{UnixListener, UnixStream};
-use tokio::process::Command;
-use tokio::sync::{mpsc, Mutex};
+use tokio::process::{Child, Command};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
 use tokio::time::{self, timeout, Duration};
 use tracing::{error, info, warn};
 
@@ -42,13 +65,46 @@ const TIMEOUT_DURATION: Duration = Duration::from_secs(5);
 const CLIPBOARD_CHECK_INTERVAL: Duration = Duration::from_millis(500);
 const PING_INTERVAL: Duration = Duration::from_secs(3);
 const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+/// A peer not heard from for this long counts as gone for new socket
+/// requests, well before `PONG_TIMEOUT` ends the connection.
+const PEER_STALE_AFTER: Duration = Duration::from_secs(6);
+/// How often `open --wait-for-client` retries the control socket.
+const PEER_WAIT_RETRY: Duration = Duration::from_millis(500);
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(3);
+/// How long a `pbcopy` shim waits for the peer to confirm it set the
+/// clipboard; generous, as the `clip` may queue behind a transfer.
+const CLIP_ACK_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the daemon keeps requests waiting for a session to reattach
+/// before failing them.
+const SESSION_GRACE: Duration = Duration::from_secs(60);
 
+// Default transfer limits; a client can change its own with
+// `--open-max-*`.
 const MAX_OPEN_FILE_SIZE: u64 = 50 * 1024 * 1024;
-const MAX_OPEN_FILES: usize = 1024;
+const MAX_OPEN_FILES: u32 = 1024;
 const MAX_OPEN_TOTAL: u64 = 250 * 1024 * 1024;
 const OPEN_CHUNK_SIZE: usize = 256 * 1024;
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const OPEN_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+const OPEN_OUTPUT_MAX: usize = 16 * 1024;
+/// A request that gets no chunk for this long is dropped as abandoned.
+const OPEN_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Permission bits received files keep from the sender by default: read
+/// and write, not execute.
+const DEFAULT_MODE_MASK: u32 = 0o666;
+/// Messages waiting to be written to the peer. Socket clients block once it
+/// is full, which stops reading their socket and so throttles the `open`
+/// or `push` process streaming into it: a transfer holds at most this many
+/// chunks in memory whatever the file size and link speed.
+const OUTBOUND_QUEUE: usize = 8;
 
-const DEFAULT_OPEN_ALLOWLIST: &str = "pdf,png,jpg,jpeg,gif,webp,svg,txt,md,html,htm,csv,json,log,mp4,mov,mp3,wav,zip";
+const DEFAULT_OPEN_ALLOWLIST: &str = "pdf,png,jpg,jpeg,gif,webp,svg,txt,md,\
+                                      html,htm,csv,json,log,mp4,mov,mp3,wav,\
+                                      zip";
+const DEFAULT_EDIT_ALLOWLIST: &str = "txt,md,rst,json,yaml,yml,toml,ini,cfg,\
+                                      conf,csv,log,xml,html,htm,css,js,ts,jsx,\
+                                      tsx,py,rb,go,rs,c,h,cc,cpp,hpp,java,kt,\
+                                      swift,sh,sql,lua,php,pl,tex,diff,patch";
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -63,13 +119,25 @@ enum Cmd {
     Server(ServerCmd),
 
     #[command(name = "client")]
-    Client(ClientCmd),
+    Client(Box<ClientCmd>),
 
     #[command(name = "generate")]
     Generate(GenerateCmd),
 
     #[command(name = "deploy")]
     Deploy(deploy::DeployCmd),
+
+    /// Delete old request directories under the open base dir
+    #[command(name = "gc")]
+    Gc(gc::GcCmd),
+
+    /// Copy local files to the remote through a running client
+    #[command(name = "push")]
+    Push(PushCmd),
+
+    /// Edit a remote file in an editor on the Mac, writing saves back
+    #[command(name = "edit")]
+    Edit(EditCmd),
 }
 
 #[derive(Args, Debug)]
@@ -87,6 +155,22 @@ struct ServerCmd {
     /// or `/tmp/clipcast-$USER.sock`).
     #[arg(long, default_value = "")]
     control_socket: String,
+
+    /// Directory that `clipcast push` from the Mac saves into when no
+    /// `--to` is given. Supports `~/` prefix.
+    #[arg(long, default_value = "~/.clipcast/inbox")]
+    inbox_dir: String,
+
+    /// Relay this session to the per-user daemon, starting it if none is
+    /// running, instead of serving it here. The daemon keeps the control
+    /// socket and queued requests across reconnects.
+    #[arg(long, conflicts_with = "daemon")]
+    attach: bool,
+
+    /// Run as the per-user daemon that `--attach` sessions connect to,
+    /// logging to stdout.
+    #[arg(long)]
+    daemon: bool,
 }
 
 #[derive(Args, Debug)]
@@ -123,10 +207,26 @@ struct ClientCmd {
     #[arg(long, default_value = "")]
     remote_control_socket: String,
 
+    /// Attach to a long-lived remote daemon (`clipcast server --attach`)
+    /// rather than starting a fresh server per connection, so the remote
+    /// control socket and queued requests survive reconnects.
+    #[arg(long)]
+    remote_daemon: bool,
+
     /// Local command used to open synced files on the Mac.
     #[arg(long, default_value = "open")]
     local_open_cmd: String,
 
+    /// Editor run for `clipcast edit` on the remote. It must not return
+    /// until the file is closed; the remote command waits for it.
+    #[arg(long, default_value = "code --wait")]
+    edit_cmd: String,
+
+    /// Comma-separated list of file extensions `edit_cmd` may be run on;
+    /// the `--open-allowlist` of `clipcast edit`.
+    #[arg(long, default_value = DEFAULT_EDIT_ALLOWLIST)]
+    edit_allowlist: String,
+
     /// Comma-separated list of file extensions (lowercased, no dot) that
     /// are allowed to be passed to `local_open_cmd`. Files outside this
     /// list are still saved under `open_base_dir` but are NOT opened.
@@ -137,6 +237,98 @@ struct ClientCmd {
     /// `~/` prefix.
     #[arg(long, default_value = "~/.clipcast/remote")]
     open_base_dir: String,
+
+    /// Keep each received file at `<open_base_dir>/<host>/mirror/<remote
+    /// absolute path>`, replacing the previous copy, instead of in a new
+    /// directory per request. Applies to plain files opened in place.
+    #[arg(long)]
+    open_mirror: bool,
+
+    /// Comma-separated `REMOTE=LOCAL` path prefixes for remote
+    /// directories mounted on this machine (sshfs, NFS), e.g.
+    /// `/home/me=~/mnt/ec2`. A file found under the mapped path with the
+    /// same size and mtime is opened there instead of being transferred.
+    #[arg(long, default_value = "")]
+    open_path_map: String,
+
+    /// Keep the executable bits of received files. By default they get
+    /// the sender's read and write bits only; setuid, setgid and sticky
+    /// are never kept.
+    #[arg(long)]
+    open_keep_exec: bool,
+
+    /// JSON file mapping remote shim names (the `argv[0]` of symlinks
+    /// created with `deploy --symlinks`) to local actions, e.g.
+    /// `{"preview": {"cmd": "open -a Preview"}, "dl": {"save_to":
+    /// "~/Downloads"}}`. Each entry takes `cmd`, `allowlist` and
+    /// `save_to`. The built-in `open` (`--local-open-cmd`,
+    /// `--open-allowlist`) and `edit` (`--edit-cmd`, `--edit-allowlist`)
+    /// entries can be overridden here. Names with no entry are handled
+    /// like `open` unless `--open-handlers-strict` is set.
+    #[arg(long, default_value = "")]
+    open_handlers: String,
+
+    /// Refuse shim names with no `--open-handlers` entry instead of
+    /// handling them like `open`.
+    #[arg(long)]
+    open_handlers_strict: bool,
+
+    /// JSON policy file with per-host rules for received files: which
+    /// extensions are opened or only saved, content sniffing, and size
+    /// caps (see README). Empty = open what the handler allows.
+    #[arg(long, default_value = "")]
+    open_policy: String,
+
+    /// Largest file accepted from the remote. The remote `open` learns this
+    /// and the other limits when it connects.
+    #[arg(long, default_value = "50M")]
+    open_max_file_size: String,
+
+    /// Most bytes accepted in one request.
+    #[arg(long, default_value = "250M")]
+    open_max_total_size: String,
+
+    /// Most files in one request (and subdirectories per directory).
+    #[arg(long, default_value_t = MAX_OPEN_FILES)]
+    open_max_files: u32,
+
+    /// Command asked before refusing a file by its extension, and before
+    /// opening an extension the host never sent before. It gets the host,
+    /// file names and sizes (JSON on stdin, `CLIPCAST_HOST`,
+    /// `CLIPCAST_FILES`) and prints `allow-once`, `allow-always` (added
+    /// to the handler's allowlist in `--open-handlers`) or `deny`. Empty =
+    /// refuse without asking.
+    #[arg(long, default_value = "")]
+    open_confirm_cmd: String,
+
+    /// How long to wait for `--open-confirm-cmd` before denying.
+    #[arg(long, default_value = "30s")]
+    open_confirm_timeout: String,
+
+    /// Where the extensions allowed from each host are kept, so that each
+    /// is asked about once.
+    #[arg(long, default_value = "~/.clipcast/confirmed.json")]
+    open_confirm_store: String,
+
+    #[command(flatten)]
+    retention: gc::RetentionArgs,
+}
+
+#[derive(Args, Debug)]
+struct PushCmd {
+    /// SSH host of the running `clipcast client` to send through
+    #[arg(long)]
+    host: String,
+
+    /// Remote directory to save into; it must exist. Relative paths start
+    /// at the remote home directory. Default: the server's
+    /// `--inbox-dir`.
+    #[arg(long)]
+    to: Option<String>,
+
+    /// Local files or directories to send
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
 }
 
 #[derive(Debug, Copy, Clone, ValueEnum)]
@@ -149,6 +341,12 @@ pub enum Shell {
     Fish,
 }
 
+#[derive(Args, Debug)]
+struct EditCmd {
+    /// File to edit; every save on the Mac is written back to it
+    path: PathBuf,
+}
+
 #[derive(Args, Debug)]
 struct GenerateCmd {
     /// Generate shell completion script
@@ -160,60 +358,382 @@ struct GenerateCmd {
 #[serde(tag = "type")]
 enum Message {
     #[serde(rename = "ping")]
-    Ping,
+    Ping {
+        /// The limits `open_begin` is checked against, from a receiver.
+        /// They ride on `ping` because peers that predate them ignore an
+        /// unknown field but drop the connection over an unknown type.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limits: Option<OpenLimits>,
+    },
     #[serde(rename = "pong")]
     Pong,
     #[serde(rename = "clip")]
-    Clip { clip: String },
+    Clip {
+        clip: String,
+        /// Set by a local `pbcopy` shim, which waits for the peer's ack;
+        /// stripped before the message goes to the peer.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<u64>,
+    },
     #[serde(rename = "ack")]
     Ack,
+    #[serde(rename = "bye")]
+    Bye,
     #[serde(rename = "open_begin")]
     OpenBegin {
         request_id: u64,
+        /// `argv[0]` of the remote shim (`open`, `preview`, ...); picks
+        /// the receiver's handler. Empty from older senders = `open`.
+        #[serde(default)]
+        name: String,
         files: Vec<OpenFileMeta>,
+        /// Directory arguments; their files are listed in `files` with
+        /// `tree` set.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        trees: Vec<OpenTreeMeta>,
         extra_args: Vec<ArgSlot>,
+        /// Directory to save into instead of running a handler (`push`
+        /// only; the Mac refuses it).
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dest: Option<String>,
+        /// `open --follow`: the receiver remembers where the files went so
+        /// later `update_of` requests can replace them.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        follow: bool,
+        /// New contents for the files of the earlier `follow` request with
+        /// this id, in the same order. They replace the local copies in
+        /// place; nothing is opened.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        update_of: Option<u64>,
+        /// `clipcast edit`: run the handler as an editor on the one file
+        /// and send its saves back as `edit_write` until it exits.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        edit: bool,
     },
+    /// Sender -> receiver when an `open --follow` ends: forget its paths.
+    #[serde(rename = "open_unfollow")]
+    OpenUnfollow { request_id: u64 },
+    /// Sender -> receiver when the socket client went away before its
+    /// result: drop the request and whatever was written for it.
+    #[serde(rename = "open_cancel")]
+    OpenCancel { request_id: u64 },
+    /// Relay -> socket client when the session that carried its
+    /// `open_begin` ended before the result: the receiver lost the
+    /// request, so send it again under a new id.
+    #[serde(rename = "open_restart")]
+    OpenRestart { request_id: u64 },
+    /// Receiver -> sender after `open_begin` (requests with files only):
+    /// the file indices already filled from the content cache, which the
+    /// sender must not stream.
+    #[serde(rename = "open_have")]
+    OpenHave { request_id: u64, indices: Vec<u32> },
     #[serde(rename = "open_chunk")]
     OpenChunk { request_id: u64, index: u32, data_b64: String, eof: bool },
+    /// Receiver -> sender while chunks arrive, at most every
+    /// `OPEN_PROGRESS_INTERVAL`: bytes written so far for the request.
+    #[serde(rename = "open_progress")]
+    OpenProgress { request_id: u64, bytes: u64 },
+    /// Receiver -> sender while a `clipcast edit` editor runs: the file
+    /// was saved with these contents.
+    #[serde(rename = "edit_write")]
+    EditWrite { request_id: u64, data_b64: String },
     #[serde(rename = "open_result")]
-    OpenResult { request_id: u64, ok: bool, error: Option<String> },
+    OpenResult {
+        request_id: u64,
+        ok: bool,
+        error: Option<String>,
+        /// Where the files ended up, for handlers that only save them, and
+        /// files the receiver's policy saved without opening.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        paths: Vec<String>,
+        /// The handler command's outcome, when one ran.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        run: Option<OpenRun>,
+    },
+    /// Socket client -> relay, first thing: answer `peer_status` once the
+    /// peer is connected, or after `timeout_ms` (0 = right away).
+    #[serde(rename = "peer_wait")]
+    PeerWait { timeout_ms: u64 },
+    /// With the limits the peer advertised, if it did.
+    #[serde(rename = "peer_status")]
+    PeerStatus {
+        connected: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limits: Option<OpenLimits>,
+    },
+    #[serde(rename = "clip_get")]
+    ClipGet { request_id: u64 },
+    #[serde(rename = "clip_value")]
+    ClipValue { request_id: u64, clip: String },
+    /// Any type this version doesn't know, from a newer peer; skipped.
+    #[serde(other)]
+    Unknown,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// What the receiver's handler command did with the files.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct OpenRun {
+    /// `None` when a signal ended the command.
+    exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signal: Option<i32>,
+    /// Output of the command, each cut to `OPEN_OUTPUT_MAX` bytes.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    stdout: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    stderr: String,
+    /// Local paths the command was run on.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    opened: Vec<String>,
+}
+
+/// Per-request transfer limits. The receiver enforces its own; the sender
+/// learns them from the peer's `limits` message to fail early.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+struct OpenLimits {
+    max_file_size: u64,
+    max_total_size: u64,
+    /// Files per request, and subdirectories per directory argument.
+    max_files: u32,
+}
+
+impl Default for OpenLimits {
+    fn default() -> Self {
+        OpenLimits {
+            max_file_size: MAX_OPEN_FILE_SIZE,
+            max_total_size: MAX_OPEN_TOTAL,
+            max_files: MAX_OPEN_FILES,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 struct OpenFileMeta {
+    /// For display; see `basename_raw`.
     basename: String,
+    /// Base64 of the original name when it isn't UTF-8. `basename` then
+    /// shows it with the bad bytes as `\xNN`, and the receiver saves the
+    /// file under `local_name` of these bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    basename_raw: Option<String>,
     size: u64,
+    /// Index into `OpenBegin::trees` for files inside a directory
+    /// argument.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tree: Option<u32>,
+    /// `/`-separated path below the tree root (tree files only).
+    /// Components that aren't UTF-8 are sent already mapped by
+    /// `local_name`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    rel_path: String,
+    /// Lowercase hex SHA-256 of the contents. Lets the receiver serve the
+    /// file from its cache instead of having it streamed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    /// Canonical path of the file on the sender, for file arguments whose
+    /// path is UTF-8. See `ClientCmd::open_mirror` and `open_path_map`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    remote_path: Option<String>,
+    /// Modification time on the sender, in seconds since the epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mtime: Option<i64>,
+    /// Permission bits on the sender; the receiver keeps those in its
+    /// `mode_mask`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<u32>,
+}
+
+/// A directory argument, recreated under the request dir.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct OpenTreeMeta {
+    basename: String,
+    /// As `OpenFileMeta::basename_raw`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    basename_raw: Option<String>,
+    /// Every subdirectory, `/`-separated and parents first, so empty ones
+    /// survive the trip.
+    #[serde(default)]
+    dirs: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "kind", rename_all = "lowercase")]
 enum ArgSlot {
-    Literal { value: String },
-    File { index: u32 },
+    Literal {
+        value: String,
+        /// Base64 of the original argument when it isn't UTF-8; `value`
+        /// is then only for display.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value_raw: Option<String>,
+    },
+    File {
+        index: u32,
+    },
+    Tree {
+        index: u32,
+    },
 }
 
 struct ReceiverCtx {
     host: String,
     base_dir: PathBuf,
-    allowlist: HashSet<String>,
-    open_cmd: String,
+    /// Content-addressed copies of received files, named by SHA-256.
+    /// `None` for `push` on the remote server, which no gc sweeps.
+    cache_dir: Option<PathBuf>,
+    /// Whether the peer may name a directory to save into
+    /// (`OpenBegin::dest`). Only the remote side, receiving from the Mac,
+    /// allows it.
+    accept_dest: bool,
+    /// Save plain files at their `remote_path` below the host directory.
+    mirror: bool,
+    /// `--open-path-map`, longest remote prefix first.
+    path_map: Vec<(String, PathBuf)>,
+    /// Which of the sender's permission bits received files keep.
+    mode_mask: u32,
+    policy: Arc<OpenPolicy>,
+    /// What `policy` allows at most, advertised to the peer.
+    limits: OpenLimits,
+    /// Prompt for files the policy refuses by name, if configured.
+    confirm: Option<Arc<Confirmer>>,
+    handlers: HashMap<String, Arc<OpenHandler>>,
+    /// Whether a shim name without a handler is refused rather than
+    /// handled like `open`.
+    strict_handlers: bool,
     states: HashMap<u64, ReceiverState>,
+    /// Requests whose cache hits are still being copied in, handed back
+    /// by the fill task before it sends `open_have`.
+    filling: HashMap<u64, oneshot::Receiver<ReceiverState>>,
+    /// Local copies of `open --follow` requests, by request id. Owned by
+    /// the client, so following outlives a reconnect.
+    follows: Arc<Mutex<HashMap<u64, Vec<FollowedFile>>>>,
+    outbound_tx: mpsc::Sender<Message>,
+}
+
+impl ReceiverCtx {
+    /// Takes back the requests whose cache fill is done, and forgets the
+    /// ones it gave up on.
+    fn settle_fills(&mut self) {
+        self.filling.retain(|request_id, rx| match rx.try_recv() {
+            Ok(state) => {
+                self.states.insert(*request_id, state);
+                false
+            }
+            Err(oneshot::error::TryRecvError::Empty) => true,
+            Err(oneshot::error::TryRecvError::Closed) => false,
+        });
+    }
+}
+
+impl Drop for ReceiverCtx {
+    /// A closed connection strands the requests still being streamed.
+    fn drop(&mut self) {
+        for (request_id, state) in self.states.drain() {
+            discard_open(request_id, state);
+        }
+        // A fill still running sees the closed channel and cleans up.
+        for (request_id, mut rx) in self.filling.drain() {
+            if let Ok(state) = rx.try_recv() {
+                discard_open(request_id, state);
+            }
+        }
+    }
+}
+
+struct FollowedFile {
+    path: PathBuf,
+    /// Digest of the contents last written there.
+    sha256: Option<String>,
+}
+
+/// What the receiver does with the files of one shim name.
+#[derive(Debug)]
+struct OpenHandler {
+    /// Command run with the rebuilt argument vector; `None` = save only.
+    cmd: Option<String>,
+    /// Extensions `cmd` may be run on, or saved if there is none.
+    allowlist: HashSet<String>,
+    /// Move received files into this directory instead of leaving them in
+    /// the per-request directory under `open_base_dir`.
+    save_to: Option<PathBuf>,
+    /// Take whatever arrives without judging it: pushes from the Mac,
+    /// which its own user sent.
+    trusted: bool,
+}
+
+/// One entry of the `--open-handlers` JSON file, keyed by shim name.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct OpenHandlerConfig {
+    cmd: Option<String>,
+    allowlist: Option<String>,
+    save_to: Option<String>,
 }
 
 struct ReceiverState {
+    handler: Arc<OpenHandler>,
+    /// Shim name the handler was picked by, for the confirmation prompt.
+    handler_name: String,
+    policy: Arc<OpenPolicy>,
+    confirm: Option<Arc<Confirmer>>,
+    /// Per-request directory the files were written into, if any.
+    dir: Option<PathBuf>,
     paths: Vec<PathBuf>,
+    /// Where each file goes once complete, for `--open-mirror`; empty
+    /// when not mirroring.
+    mirror: Vec<Option<PathBuf>>,
+    tree_paths: Vec<PathBuf>,
     handles: Vec<Option<tfs::File>>,
+    /// Running digest and byte count of each file still being streamed.
+    hashers: Vec<Sha256>,
+    received: Vec<u64>,
+    files: Vec<OpenFileMeta>,
+    /// Files that failed the size or digest check at `eof`, already
+    /// deleted; any entry here fails the request.
+    corrupt: Vec<String>,
+    /// For `update_of` requests: the followed request and the path each
+    /// file replaces, `None` where the contents are unchanged.
+    replace: Option<(u64, Vec<Option<PathBuf>>)>,
+    /// For `--follow` requests: what to follow once the files are open.
+    follow: Option<Vec<FollowedFile>>,
     extra_args: Vec<ArgSlot>,
     remaining: usize,
+    /// When the last `open_progress` went out.
+    progress_at: time::Instant,
+    /// When the last chunk arrived; see `OPEN_IDLE_TIMEOUT`.
+    last_chunk: time::Instant,
+    /// A `clipcast edit` request; finished by `edit_session`.
+    edit: bool,
 }
 
 struct RelayCtx {
     pending: Arc<Mutex<HashMap<u64, mpsc::UnboundedSender<Message>>>>,
+    /// The limits the peer advertised; `None` until it does, and for
+    /// peers that predate them.
+    peer_limits: Arc<Mutex<Option<OpenLimits>>>,
+    /// Whether the peer has been heard from lately; see
+    /// `PEER_STALE_AFTER`.
+    peer_alive: watch::Sender<bool>,
 }
 
-enum OpenRole {
-    Receiver(ReceiverCtx),
-    Relay(RelayCtx),
+/// Both ends of the connection play both parts: the Mac receives remote
+/// `open` requests and relays local `push` requests, the remote server
+/// the other way round.
+struct OpenCtx {
+    /// Requests arriving from the peer.
+    receiver: ReceiverCtx,
+    /// Requests from local socket clients, awaiting the peer's reply.
+    relay: RelayCtx,
+}
+
+impl OpenCtx {
+    /// True while a request is still being streamed or awaiting its
+    /// result, in either direction; shutdown waits for these to drain.
+    async fn in_flight(&mut self) -> bool {
+        self.receiver.settle_fills();
+        !self.receiver.states.is_empty()
+            || !self.receiver.filling.is_empty()
+            || !self.relay.pending.lock().await.is_empty()
+    }
 }
 
 struct Server {
@@ -225,78 +745,542 @@ impl Server {
         Server { cmd }
     }
 
-    async fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let socket_path = if self.cmd.control_socket.is_empty() {
+    fn control_socket(&self) -> PathBuf {
+        if self.cmd.control_socket.is_empty() {
             default_control_socket()
         } else {
             PathBuf::from(&self.cmd.control_socket)
-        };
-        if let Some(parent) = socket_path.parent() {
-            let _ = std::fs::create_dir_all(parent);
         }
-        let _ = std::fs::remove_file(&socket_path);
-        let listener = UnixListener::bind(&socket_path)?;
-        let _ = std::fs::set_permissions(
-            &socket_path,
-            std::fs::Permissions::from_mode(0o600),
-        );
-        info!("control socket listening at {}", socket_path.display());
+    }
 
-        let (outbound_tx, outbound_rx) = mpsc::unbounded_channel::<Message>();
-        let pending: Arc<Mutex<HashMap<u64, mpsc::UnboundedSender<Message>>>> =
-            Arc::new(Mutex::new(HashMap::new()));
+    /// Creates the inbox. Pushes from the Mac are staged below it, then
+    /// moved into it (or into their `--to` directory) once complete.
+    fn inbox(&self) -> std::io::Result<PathBuf> {
+        let inbox = expand_home(&self.cmd.inbox_dir);
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&inbox)?;
+        Ok(inbox)
+    }
 
-        let accept_handle = {
-            let outbound_tx = outbound_tx.clone();
-            let pending = pending.clone();
-            tokio::spawn(async move {
-                loop {
-                    match listener.accept().await {
-                        Ok((stream, _addr)) => {
-                            let tx = outbound_tx.clone();
-                            let pending = pending.clone();
-                            tokio::spawn(handle_socket_client(
-                                stream, tx, pending,
-                            ));
-                        }
-                        Err(e) => {
-                            error!("socket accept error: {}", e);
-                            break;
-                        }
-                    }
-                }
-            })
-        };
+    async fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.cmd.attach {
+            return run_attach(&self.cmd, &self.control_socket()).await;
+        }
+        if self.cmd.daemon {
+            return self.run_daemon().await;
+        }
+        let socket_path = self.control_socket();
+        let mut shutdown = ShutdownSignal::new()?;
 
-        let _sentinel = outbound_tx;
+        let (outbound_tx, outbound_rx) =
+            mpsc::channel::<Message>(OUTBOUND_QUEUE);
+        let pending: Arc<Mutex<HashMap<u64, mpsc::UnboundedSender<Message>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let peer_limits = Arc::new(Mutex::new(None));
+        let (peer_alive, _) = watch::channel(false);
+        let (_socket_guard, accept_handle) = listen_control_socket(
+            &socket_path,
+            &outbound_tx,
+            &pending,
+            &peer_limits,
+            &peer_alive,
+        )?;
+        let receiver = push_receiver(&self.inbox()?, outbound_tx);
 
         let stdin = tokio::io::stdin();
         let mut stdout = tokio::io::stdout();
         let reader = BufReader::new(stdin);
         let lines = reader.lines();
 
-        let mut role = OpenRole::Relay(RelayCtx { pending });
+        let mut open = OpenCtx {
+            receiver,
+            relay: RelayCtx { pending, peer_limits, peer_alive },
+        };
         let result = run_message_loop(
             &self.cmd.read_clipboard_cmd,
             &self.cmd.write_clipboard_cmd,
             &mut stdout,
             lines,
-            outbound_rx,
-            &mut role,
+            &mut Outbox::new(outbound_rx),
+            &mut open,
+            &mut shutdown,
         )
         .await;
 
+        open.relay.peer_alive.send_replace(false);
+        accept_handle.abort();
+        match result {
+            Ok(exit) => {
+                info!("server exiting: {:?}", exit);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// `--daemon`: serves one attached session at a time, keeping the
+    /// control socket, the clipboard state and queued requests between
+    /// them. A new session replaces the current one, whose ssh
+    /// connection is most likely already dead.
+    async fn run_daemon(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let socket_path = self.control_socket();
+        let attach_path = daemon_socket(&socket_path);
+        // Held until we exit; only its holder may replace the sockets.
+        let Some(_lock) = lock_daemon(&socket_path)? else {
+            return Err(format!(
+                "a daemon is already running at {}",
+                attach_path.display()
+            )
+            .into());
+        };
+        let mut shutdown = ShutdownSignal::new()?;
+
+        let (outbound_tx, outbound_rx) =
+            mpsc::channel::<Message>(OUTBOUND_QUEUE);
+        let pending: Arc<Mutex<HashMap<u64, mpsc::UnboundedSender<Message>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let peer_limits = Arc::new(Mutex::new(None));
+        let (peer_alive, _) = watch::channel(false);
+        let (_socket_guard, accept_handle) = listen_control_socket(
+            &socket_path,
+            &outbound_tx,
+            &pending,
+            &peer_limits,
+            &peer_alive,
+        )?;
+
+        let _ = std::fs::remove_file(&attach_path);
+        let attach_listener = UnixListener::bind(&attach_path)?;
+        let _ = std::fs::set_permissions(
+            &attach_path,
+            std::fs::Permissions::from_mode(0o600),
+        );
+        let _attach_guard = SocketGuard::new(attach_path.clone());
+        info!("daemon waiting for sessions at {}", attach_path.display());
+
+        let inbox = self.inbox()?;
+        let mut open = OpenCtx {
+            receiver: push_receiver(&inbox, outbound_tx.clone()),
+            relay: RelayCtx { pending, peer_limits, peer_alive },
+        };
+        let mut outbox = Outbox::new(outbound_rx);
+        let mut next: Option<UnixStream> = None;
+        let result = loop {
+            let stream = match next.take() {
+                Some(stream) => stream,
+                None => match wait_for_session(
+                    &attach_listener,
+                    &open,
+                    &mut outbox,
+                    &mut shutdown,
+                )
+                .await
+                {
+                    Ok(Some(stream)) => stream,
+                    Ok(None) => break Ok(()),
+                    Err(e) => break Err(e),
+                },
+            };
+            info!("session attached");
+            let (reader, mut writer) = stream.into_split();
+            let lines = BufReader::new(reader).lines();
+            let session = run_message_loop(
+                &self.cmd.read_clipboard_cmd,
+                &self.cmd.write_clipboard_cmd,
+                &mut writer,
+                lines,
+                &mut outbox,
+                &mut open,
+                &mut shutdown,
+            );
+            let result = tokio::select! {
+                result = session => result,
+                accepted = attach_listener.accept() => match accepted {
+                    Ok((stream, _addr)) => {
+                        next = Some(stream);
+                        Err("replaced by a new session".into())
+                    }
+                    Err(e) => break Err(e.into()),
+                },
+            };
+            end_session(&mut open, &mut outbox).await;
+            open.receiver = push_receiver(&inbox, outbound_tx.clone());
+            match result {
+                Ok(LoopExit::Shutdown) => break Ok(()),
+                Ok(LoopExit::PeerClosed) => info!("session detached"),
+                Err(e) => warn!("session ended: {}", e),
+            }
+        };
+
         accept_handle.abort();
-        let _ = std::fs::remove_file(&socket_path);
+        info!("daemon exiting");
         result
     }
 }
 
+/// The receiving side of the remote server: only `clipcast push` from the
+/// Mac sends it files, saved into `inbox`.
+fn push_receiver(
+    inbox: &Path,
+    outbound_tx: mpsc::Sender<Message>,
+) -> ReceiverCtx {
+    let mut handlers = HashMap::new();
+    handlers.insert(
+        "push".to_string(),
+        Arc::new(OpenHandler {
+            cmd: None,
+            allowlist: HashSet::new(),
+            save_to: Some(inbox.to_path_buf()),
+            trusted: true,
+        }),
+    );
+    ReceiverCtx {
+        host: String::new(),
+        base_dir: inbox.join(".staging"),
+        cache_dir: None,
+        accept_dest: true,
+        mirror: false,
+        path_map: Vec::new(),
+        mode_mask: DEFAULT_MODE_MASK,
+        policy: Arc::new(OpenPolicy::builtin(OpenLimits::default())),
+        limits: OpenLimits::default(),
+        confirm: None,
+        handlers,
+        strict_handlers: true,
+        states: HashMap::new(),
+        filling: HashMap::new(),
+        follows: Arc::new(Mutex::new(HashMap::new())),
+        outbound_tx,
+    }
+}
+
+/// Cleans up after a session leaves the daemon. What is still queued
+/// stays for the next session, and so do the requests this one passed on:
+/// clipboard requests are sent again, while the clients of open requests,
+/// whose files the peer dropped with the session, are told to start over.
+/// Nothing fails before `SESSION_GRACE`; see `wait_for_session`.
+async fn end_session(open: &mut OpenCtx, outbox: &mut Outbox) {
+    open.relay.peer_alive.send_replace(false);
+    // The next session may bring a different clipcast.
+    *open.relay.peer_limits.lock().await = None;
+    let mut pending = open.relay.pending.lock().await;
+    for (request_id, resend) in outbox.sent.drain(..) {
+        match resend {
+            Some(msg) if pending.contains_key(&request_id) => {
+                outbox.resend.push_back(msg);
+            }
+            Some(_) => {}
+            None => {
+                if let Some(sender) = pending.remove(&request_id) {
+                    info!("request_id={} interrupted; restarting", request_id);
+                    outbox.dropped.insert(request_id);
+                    let _ = sender.send(Message::OpenRestart { request_id });
+                }
+            }
+        }
+    }
+}
+
+/// Waits for the next session to attach; `None` on shutdown. Requests
+/// keep their place for `SESSION_GRACE`, then fail, as do any that arrive
+/// later before a session does.
+async fn wait_for_session(
+    listener: &UnixListener,
+    open: &OpenCtx,
+    outbox: &mut Outbox,
+    shutdown: &mut ShutdownSignal,
+) -> Result<Option<UnixStream>, Box<dyn std::error::Error>> {
+    let grace = time::sleep(SESSION_GRACE);
+    tokio::pin!(grace);
+    let mut expired = false;
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => return Ok(Some(accepted?.0)),
+            _ = shutdown.recv() => return Ok(None),
+            _ = &mut grace, if !expired => {
+                expired = true;
+                expire_pending(open, outbox).await;
+            }
+            Some(msg) = outbox.rx.recv(), if expired => {
+                if let Message::Clip { .. } = msg {
+                    outbox.resend.push_back(msg);
+                }
+                // Whatever request it belongs to can't be served now.
+                expire_pending(open, outbox).await;
+            }
+        }
+    }
+}
+
+/// Fails the requests still waiting for a session and skips what is
+/// queued for them, except a copied clipboard, which the next session
+/// should still deliver.
+async fn expire_pending(open: &OpenCtx, outbox: &mut Outbox) {
+    for (request_id, sender) in open.relay.pending.lock().await.drain() {
+        info!("request_id={} failed: client disconnected", request_id);
+        outbox.dropped.insert(request_id);
+        let _ = sender.send(Message::OpenResult {
+            request_id,
+            ok: false,
+            error: Some("clipcast client disconnected".to_string()),
+            paths: Vec::new(),
+            run: None,
+        });
+    }
+    outbox.resend.retain(|msg| matches!(msg, Message::Clip { .. }));
+    while let Ok(msg) = outbox.rx.try_recv() {
+        if let Message::Clip { .. } = msg {
+            outbox.resend.push_back(msg);
+        }
+    }
+}
+
+/// `server --attach`: connects this ssh session's stdio to the daemon,
+/// starting one if none answers, until either side hangs up.
+async fn run_attach(
+    cmd: &ServerCmd,
+    control_socket: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let attach_path = daemon_socket(control_socket);
+    let stream = match UnixStream::connect(&attach_path).await {
+        Ok(stream) => stream,
+        Err(_) => {
+            spawn_daemon(cmd)?;
+            let deadline = time::Instant::now() + TIMEOUT_DURATION;
+            loop {
+                match UnixStream::connect(&attach_path).await {
+                    Ok(stream) => break stream,
+                    Err(e) if time::Instant::now() >= deadline => {
+                        return Err(format!(
+                            "daemon did not start ({}: {}); see {}",
+                            attach_path.display(),
+                            e,
+                            daemon_log().display()
+                        )
+                        .into());
+                    }
+                    Err(_) => time::sleep(PEER_WAIT_RETRY).await,
+                }
+            }
+        }
+    };
+    let (mut from_daemon, mut to_daemon) = stream.into_split();
+    let upstream = async {
+        let _ = tokio::io::copy(&mut tokio::io::stdin(), &mut to_daemon).await;
+        // Our peer is gone; let the daemon see it and finish up.
+        let _ = to_daemon.shutdown().await;
+        std::future::pending::<()>().await
+    };
+    let mut stdout = tokio::io::stdout();
+    tokio::select! {
+        _ = upstream => {}
+        result = tokio::io::copy(&mut from_daemon, &mut stdout) => {
+            result?;
+        }
+    }
+    Ok(())
+}
+
+/// Starts `server --daemon` with this server's settings, detached from the
+/// ssh session so it outlives it.
+fn spawn_daemon(cmd: &ServerCmd) -> Result<(), Box<dyn std::error::Error>> {
+    let log_path = daemon_log();
+    if let Some(parent) = log_path.parent() {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)?;
+    }
+    let log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)?;
+    let mut daemon = Command::new(std::env::current_exe()?);
+    daemon
+        .args(["server", "--daemon"])
+        .arg("--write-clipboard-cmd")
+        .arg(&cmd.write_clipboard_cmd)
+        .arg("--read-clipboard-cmd")
+        .arg(&cmd.read_clipboard_cmd)
+        .arg("--control-socket")
+        .arg(&cmd.control_socket)
+        .arg("--inbox-dir")
+        .arg(&cmd.inbox_dir)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .process_group(0);
+    let child = daemon.spawn()?;
+    info!("started daemon (pid {:?})", child.id());
+    Ok(())
+}
+
+/// Binds a control socket only this user can connect to, replacing any
+/// stale one, and starts relaying its clients' requests to the peer.
+/// Abort the returned task to stop accepting.
+fn listen_control_socket(
+    socket_path: &Path,
+    outbound_tx: &mpsc::Sender<Message>,
+    pending: &Arc<Mutex<HashMap<u64, mpsc::UnboundedSender<Message>>>>,
+    peer_limits: &Arc<Mutex<Option<OpenLimits>>>,
+    peer_alive: &watch::Sender<bool>,
+) -> std::io::Result<(SocketGuard, tokio::task::JoinHandle<()>)> {
+    if let Some(parent) = socket_path.parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    let _ = std::fs::remove_file(socket_path);
+    let listener = UnixListener::bind(socket_path)?;
+    let _ = std::fs::set_permissions(
+        socket_path,
+        std::fs::Permissions::from_mode(0o600),
+    );
+    let guard = SocketGuard::new(socket_path.to_path_buf());
+    info!("control socket listening at {}", socket_path.display());
+
+    let outbound_tx = outbound_tx.clone();
+    let pending = pending.clone();
+    let peer_limits = peer_limits.clone();
+    let peer_alive = peer_alive.subscribe();
+    let handle = tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _addr)) => {
+                    let tx = outbound_tx.clone();
+                    let pending = pending.clone();
+                    let limits = peer_limits.clone();
+                    let alive = peer_alive.clone();
+                    tokio::spawn(handle_socket_client(
+                        stream, tx, pending, limits, alive,
+                    ));
+                }
+                Err(e) => {
+                    error!("socket accept error: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+    Ok((guard, handle))
+}
+
+/// Removes the control socket when the server exits, however it exits.
+/// The path is only unlinked if it still refers to the socket this
+/// process bound: a newer server started by a reconnecting client may
+/// already have replaced it, and deleting that one would orphan it.
+struct SocketGuard {
+    path: PathBuf,
+    ino: Option<u64>,
+}
+
+impl SocketGuard {
+    fn new(path: PathBuf) -> Self {
+        let ino = std::fs::symlink_metadata(&path).ok().map(|m| m.ino());
+        SocketGuard { path, ino }
+    }
+}
+
+impl Drop for SocketGuard {
+    fn drop(&mut self) {
+        let current =
+            std::fs::symlink_metadata(&self.path).ok().map(|m| m.ino());
+        if current.is_some() && current == self.ino {
+            let _ = std::fs::remove_file(&self.path);
+            info!("removed control socket {}", self.path.display());
+        }
+    }
+}
+
+/// Resolves when the process receives SIGINT, SIGTERM or SIGHUP. The
+/// streams are registered once and reused across reconnects, so a signal
+/// that lands between two connections is not lost.
+struct ShutdownSignal {
+    int: Signal,
+    term: Signal,
+    hup: Signal,
+}
+
+impl ShutdownSignal {
+    fn new() -> std::io::Result<Self> {
+        Ok(ShutdownSignal {
+            int: signal(SignalKind::interrupt())?,
+            term: signal(SignalKind::terminate())?,
+            hup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    async fn recv(&mut self) {
+        tokio::select! {
+            _ = self.int.recv() => info!("received SIGINT"),
+            _ = self.term.recv() => info!("received SIGTERM"),
+            _ = self.hup.recv() => info!("received SIGHUP"),
+        }
+    }
+}
+
+/// Why `run_message_loop` returned without an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LoopExit {
+    /// We were signalled and finished the `Bye` handshake (or ran out of
+    /// grace time).
+    Shutdown,
+    /// The peer sent `Bye` and then closed the stream.
+    PeerClosed,
+}
+
+/// What `run_message_loop` hands from one connection to the next: the
+/// messages queued for the peer, and the clipboard it was last sent. The
+/// client starts each connection afresh; the daemon keeps them across
+/// attached sessions.
+struct Outbox {
+    rx: mpsc::Receiver<Message>,
+    last_clipboard: String,
+    /// Requests this connection passed on that may still await their
+    /// reply, in order, each with the message to send again should the
+    /// connection end first; `None` for an `open_begin`, which its client
+    /// has to restart. See `end_session`.
+    sent: Vec<(u64, Option<Message>)>,
+    /// Sent ahead of the queue when the next connection starts.
+    resend: VecDeque<Message>,
+    /// Requests restarted under a new id or failed; what is still queued
+    /// for them is skipped.
+    dropped: HashSet<u64>,
+}
+
+impl Outbox {
+    fn new(rx: mpsc::Receiver<Message>) -> Self {
+        Outbox {
+            rx,
+            last_clipboard: String::new(),
+            sent: Vec::new(),
+            resend: VecDeque::new(),
+            dropped: HashSet::new(),
+        }
+    }
+}
+
+/// Per-connection bookkeeping for `run_message_loop`.
+struct SessionState<'a> {
+    /// Outlives the connection where the server is a daemon, so a
+    /// reattaching session doesn't resend a clipboard the peer has seen.
+    last_clipboard: &'a mut String,
+    last_pong: time::Instant,
+    /// When the peer last sent anything.
+    last_heard: time::Instant,
+    /// `Clip`/`Bye` messages we sent that the peer has not acked yet, in
+    /// order, each with the shim request waiting for its ack if any.
+    unacked: VecDeque<Option<u64>>,
+    peer_said_bye: bool,
+}
+
+/// Sends the local clipboard if it changed. Returns whether a `Clip` was
+/// sent, so the caller can count the ack it expects back.
 async fn check_and_send_update<T>(
     read_cmd: &str,
     last_clipboard: &mut String,
     stdout: &mut T,
-) -> Result<(), Box<dyn std::error::Error>>
+) -> Result<bool, Box<dyn std::error::Error>>
 where
     T: AsyncWrite + Unpin,
 {
@@ -304,11 +1288,13 @@ where
         if current_clip != *last_clipboard {
             info!("sending clipboard: len={}", current_clip.len());
             *last_clipboard = current_clip.clone();
-            let message = Message::Clip { clip: current_clip };
+            let message =
+                Message::Clip { clip: current_clip, request_id: None };
             send_with_timeout(stdout, message).await?;
+            return Ok(true);
         }
     }
-    Ok(())
+    Ok(false)
 }
 
 async fn get_clipboard(read_cmd: &str) -> Result<String, std::io::Error> {
@@ -326,7 +1312,11 @@ async fn get_clipboard(read_cmd: &str) -> Result<String, std::io::Error> {
         ));
     }
 
-    let output = Command::new(&args[0]).args(&args[1..]).output().await?;
+    let output = Command::new(&args[0])
+        .args(&args[1..])
+        .env("CLIPCAST_NO_SHIM", "1")
+        .output()
+        .await?;
 
     Ok(String::from_utf8(output.stdout).unwrap_or_default())
 }
@@ -351,6 +1341,7 @@ async fn set_clipboard(
 
     let mut child = Command::new(&args[0])
         .args(&args[1..])
+        .env("CLIPCAST_NO_SHIM", "1")
         .stdin(Stdio::piped())
         .stdout(Stdio::null())
         .spawn()?;
@@ -394,29 +1385,68 @@ where
 
 struct Client {
     cmd: ClientCmd,
+    /// See `ReceiverCtx::follows`.
+    follows: Arc<Mutex<HashMap<u64, Vec<FollowedFile>>>>,
 }
 
 impl Client {
     fn new(cmd: ClientCmd) -> Self {
-        Client { cmd }
+        Client { cmd, follows: Arc::new(Mutex::new(HashMap::new())) }
     }
 
     async fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut shutdown = ShutdownSignal::new()?;
+        let gc_handle = self.spawn_retention()?;
+        let result = self.reconnect_loop(&mut shutdown).await;
+        gc_handle.abort();
+        result
+    }
+
+    /// Applies the retention policies to `open_base_dir` now and then
+    /// every `gc::GC_INTERVAL` for as long as the client runs.
+    fn spawn_retention(
+        &self,
+    ) -> Result<tokio::task::JoinHandle<()>, Box<dyn std::error::Error>> {
+        let policy = self.cmd.retention.policy()?;
+        let base_dir = expand_home(&self.cmd.open_base_dir);
+        Ok(tokio::spawn(async move {
+            let mut interval = time::interval(gc::GC_INTERVAL);
+            loop {
+                interval.tick().await;
+                let base_dir = base_dir.clone();
+                let _ = tokio::task::spawn_blocking(move || {
+                    gc::sweep(&base_dir, &policy)
+                })
+                .await;
+            }
+        }))
+    }
+
+    async fn reconnect_loop(
+        &mut self,
+        shutdown: &mut ShutdownSignal,
+    ) -> Result<(), Box<dyn std::error::Error>> {
         loop {
-            match self.run_connection().await {
-                Ok(()) => {}
+            match self.run_connection(shutdown).await {
+                Ok(LoopExit::Shutdown) => return Ok(()),
+                Ok(LoopExit::PeerClosed) => {
+                    info!("remote server said bye; reconnecting");
+                }
                 Err(e) => {
                     eprintln!("Connection error: {}", e);
-                    time::sleep(Duration::from_secs(1)).await;
-                    continue;
                 }
             }
+            tokio::select! {
+                _ = time::sleep(Duration::from_secs(1)) => {}
+                _ = shutdown.recv() => return Ok(()),
+            }
         }
     }
 
     async fn run_connection(
         &mut self,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+        shutdown: &mut ShutdownSignal,
+    ) -> Result<LoopExit, Box<dyn std::error::Error>> {
         let mut args: Vec<&str>;
         if self.cmd.ssh_args.is_empty() {
             args = vec![self.cmd.host.as_str()];
@@ -441,15 +1471,23 @@ impl Client {
                 .push(format!("'{}'", self.cmd.remote_control_socket.clone()));
         }
 
+        if self.cmd.remote_daemon {
+            remote_args.push("--attach".into());
+        }
+
         args.push("--");
         let remote_args = remote_args.join(" ");
         args.push(&remote_args);
         info!("connecting to remote server: {:?}", args);
 
+        // ssh gets its own process group so a terminal Ctrl-C reaches only
+        // us: we still need the channel to say `Bye` before tearing it
+        // down ourselves.
         let mut child = Command::new("ssh")
             .args(&args)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
+            .process_group(0)
             .spawn()?;
 
         let mut stdin = child.stdin.take().unwrap();
@@ -457,35 +1495,206 @@ impl Client {
         let reader = BufReader::new(stdout).lines();
 
         let base_dir = expand_home(&self.cmd.open_base_dir);
-        let allowlist: HashSet<String> = self
-            .cmd
-            .open_allowlist
-            .split(',')
-            .map(|s| s.trim().to_lowercase())
-            .filter(|s| !s.is_empty())
-            .collect();
-        let mut role = OpenRole::Receiver(ReceiverCtx {
+        let handlers = load_open_handlers(&self.cmd)?;
+        let limits = open_limits(&self.cmd)?;
+        let path_map = parse_path_map(&self.cmd.open_path_map)?;
+        let policy = Arc::new(if self.cmd.open_policy.is_empty() {
+            OpenPolicy::builtin(limits)
+        } else {
+            OpenPolicy::load(
+                &expand_home(&self.cmd.open_policy),
+                &self.cmd.host,
+                limits,
+            )?
+        });
+        let confirm = if self.cmd.open_confirm_cmd.is_empty() {
+            None
+        } else {
+            let timeout = gc::parse_duration(&self.cmd.open_confirm_timeout)
+                .map_err(|e| {
+                    format!(
+                        "--open-confirm-timeout {:?}: {}",
+                        self.cmd.open_confirm_timeout, e
+                    )
+                })?;
+            Some(Arc::new(Confirmer::new(
+                self.cmd.open_confirm_cmd.clone(),
+                timeout,
+                self.cmd.host.clone(),
+                expand_home(&self.cmd.open_confirm_store),
+                (!self.cmd.open_handlers.is_empty())
+                    .then(|| expand_home(&self.cmd.open_handlers)),
+                self.cmd.open_allowlist.clone(),
+                self.cmd.edit_allowlist.clone(),
+            )?))
+        };
+        // Finished open requests post their results here from the task
+        // that ran the handler command, as do local `push` clients.
+        let (outbound_tx, outbound_rx) =
+            mpsc::channel::<Message>(OUTBOUND_QUEUE);
+        let pending: Arc<Mutex<HashMap<u64, mpsc::UnboundedSender<Message>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let peer_limits = Arc::new(Mutex::new(None));
+        let (peer_alive, _) = watch::channel(false);
+        let (_socket_guard, accept_handle) = listen_control_socket(
+            &client_control_socket(&self.cmd.host),
+            &outbound_tx,
+            &pending,
+            &peer_limits,
+            &peer_alive,
+        )?;
+        let cache_dir = content_cache_dir(
+            &base_dir.join(sanitize_component(&self.cmd.host)),
+        );
+        let receiver = ReceiverCtx {
             host: self.cmd.host.clone(),
             base_dir,
-            allowlist,
-            open_cmd: self.cmd.local_open_cmd.clone(),
+            cache_dir: Some(cache_dir),
+            accept_dest: false,
+            mirror: self.cmd.open_mirror,
+            path_map,
+            mode_mask: if self.cmd.open_keep_exec {
+                0o777
+            } else {
+                DEFAULT_MODE_MASK
+            },
+            limits: policy.limits(),
+            policy,
+            confirm,
+            handlers,
+            strict_handlers: self.cmd.open_handlers_strict,
             states: HashMap::new(),
-        });
-
-        let (_outbound_tx, outbound_rx) = mpsc::unbounded_channel::<Message>();
-        // _outbound_tx is held for the duration of the connection so that
-        // outbound_rx never sees a closed channel. The Mac client has no
-        // external injectors; it writes directly to stdin inside dispatch.
+            filling: HashMap::new(),
+            follows: self.follows.clone(),
+            outbound_tx,
+        };
+        let mut open = OpenCtx {
+            receiver,
+            relay: RelayCtx { pending, peer_limits, peer_alive },
+        };
 
-        run_message_loop(
+        let result = run_message_loop(
             &self.cmd.read_clipboard_cmd,
             &self.cmd.write_clipboard_cmd,
             &mut stdin,
             reader,
-            outbound_rx,
-            &mut role,
+            &mut Outbox::new(outbound_rx),
+            &mut open,
+            shutdown,
         )
-        .await
+        .await;
+
+        open.relay.peer_alive.send_replace(false);
+        accept_handle.abort();
+        drop(stdin);
+        terminate_child(&mut child).await;
+        result
+    }
+}
+
+/// The `--open-max-*` flags.
+fn open_limits(
+    cmd: &ClientCmd,
+) -> Result<OpenLimits, Box<dyn std::error::Error>> {
+    let size = |flag: &str, value: &str| {
+        gc::parse_size(value)
+            .map_err(|e| format!("{} {:?}: {}", flag, value, e))
+    };
+    Ok(OpenLimits {
+        max_file_size: size("--open-max-file-size", &cmd.open_max_file_size)?,
+        max_total_size: size(
+            "--open-max-total-size",
+            &cmd.open_max_total_size,
+        )?,
+        max_files: cmd.open_max_files,
+    })
+}
+
+fn parse_allowlist(csv: &str) -> HashSet<String> {
+    csv.split(',')
+        .map(|s| s.trim().to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Builds the shim-name → handler table: the built-in `open` entry from
+/// `--local-open-cmd`/`--open-allowlist` and `edit` from
+/// `--edit-cmd`/`--edit-allowlist`, overlaid with `--open-handlers`.
+fn load_open_handlers(
+    cmd: &ClientCmd,
+) -> Result<HashMap<String, Arc<OpenHandler>>, Box<dyn std::error::Error>> {
+    let default_allowlist = parse_allowlist(&cmd.open_allowlist);
+    let edit_allowlist = parse_allowlist(&cmd.edit_allowlist);
+    let mut handlers = HashMap::new();
+    handlers.insert(
+        "open".to_string(),
+        Arc::new(OpenHandler {
+            cmd: Some(cmd.local_open_cmd.clone()),
+            allowlist: default_allowlist.clone(),
+            save_to: None,
+            trusted: false,
+        }),
+    );
+    handlers.insert(
+        "edit".to_string(),
+        Arc::new(OpenHandler {
+            cmd: Some(cmd.edit_cmd.clone()),
+            allowlist: edit_allowlist.clone(),
+            save_to: None,
+            trusted: false,
+        }),
+    );
+    if cmd.open_handlers.is_empty() {
+        return Ok(handlers);
+    }
+    let path = expand_home(&cmd.open_handlers);
+    let text = std::fs::read_to_string(&path)
+        .map_err(|e| format!("reading {}: {}", path.display(), e))?;
+    let configs: HashMap<String, OpenHandlerConfig> =
+        serde_json::from_str(&text)
+            .map_err(|e| format!("parsing {}: {}", path.display(), e))?;
+    for (name, c) in configs {
+        if let Some(cmd) = &c.cmd {
+            if shlex::split(cmd).map_or(true, |p| p.is_empty()) {
+                return Err(format!(
+                    "{}: handler {:?} has an invalid cmd {:?}",
+                    path.display(),
+                    name,
+                    cmd
+                )
+                .into());
+            }
+        }
+        let handler = OpenHandler {
+            cmd: c.cmd,
+            allowlist: c
+                .allowlist
+                .as_deref()
+                .map(parse_allowlist)
+                .unwrap_or_else(|| match name.as_str() {
+                    "edit" => edit_allowlist.clone(),
+                    _ => default_allowlist.clone(),
+                }),
+            save_to: c.save_to.as_deref().map(expand_home),
+            trusted: false,
+        };
+        info!("open handler {:?}: {:?}", name, handler);
+        handlers.insert(name, Arc::new(handler));
+    }
+    Ok(handlers)
+}
+
+/// Closes out the ssh child: give it a moment to exit on its own after
+/// its stdin was closed (the remote server sees EOF and cleans up), then
+/// kill it.
+async fn terminate_child(child: &mut Child) {
+    match timeout(SHUTDOWN_GRACE, child.wait()).await {
+        Ok(Ok(status)) => info!("ssh exited: {}", status),
+        Ok(Err(e)) => warn!("waiting for ssh failed: {}", e),
+        Err(_) => {
+            warn!("ssh did not exit after stdin closed; killing it");
+            let _ = child.kill().await;
+        }
     }
 }
 
@@ -494,42 +1703,100 @@ async fn run_message_loop<R, W>(
     write_cmd: &str,
     stdin: &mut W,
     mut reader: tokio::io::Lines<R>,
-    mut outbound_rx: mpsc::UnboundedReceiver<Message>,
-    role: &mut OpenRole,
-) -> Result<(), Box<dyn std::error::Error>>
+    outbox: &mut Outbox,
+    open: &mut OpenCtx,
+    shutdown: &mut ShutdownSignal,
+) -> Result<LoopExit, Box<dyn std::error::Error>>
 where
     R: AsyncBufRead + Unpin,
     W: AsyncWrite + Unpin,
 {
-    let mut last_clipboard = String::new();
+    let mut session = SessionState {
+        last_clipboard: &mut outbox.last_clipboard,
+        last_pong: time::Instant::now(),
+        last_heard: time::Instant::now(),
+        unacked: VecDeque::new(),
+        peer_said_bye: false,
+    };
     let mut clip_interval = time::interval(CLIPBOARD_CHECK_INTERVAL);
     let mut ping_interval = time::interval(PING_INTERVAL);
+    let mut shutdown_deadline: Option<time::Instant> = None;
 
-    let mut last_pong = time::Instant::now();
+    // What the last session left unanswered goes first.
+    while let Some(msg) = outbox.resend.pop_front() {
+        send_outbound(msg, &mut session, &mut outbox.sent, stdin).await?;
+    }
 
-    while (time::Instant::now() - last_pong) < PONG_TIMEOUT {
+    while (time::Instant::now() - session.last_pong) < PONG_TIMEOUT {
+        if shutdown_deadline.is_some()
+            && session.unacked.is_empty()
+            && !open.in_flight().await
+        {
+            info!("shutdown complete");
+            return Ok(LoopExit::Shutdown);
+        }
+        let grace = time::sleep_until(
+            shutdown_deadline
+                .unwrap_or_else(|| time::Instant::now() + PONG_TIMEOUT),
+        );
         tokio::select! {
-            _ = clip_interval.tick() => {
-                check_and_send_update(read_cmd, &mut last_clipboard, stdin).await?;
+            _ = shutdown.recv(), if shutdown_deadline.is_none() => {
+                info!("shutting down: flushing clipboard and sending bye");
+                if check_and_send_update(read_cmd, session.last_clipboard, stdin).await? {
+                    session.unacked.push_back(None);
+                }
+                send_with_timeout(stdin, Message::Bye).await?;
+                session.unacked.push_back(None);
+                shutdown_deadline = Some(time::Instant::now() + SHUTDOWN_GRACE);
+            }
+            _ = grace, if shutdown_deadline.is_some() => {
+                warn!(
+                    "shutdown grace period expired (unacked={}); exiting",
+                    session.unacked.len()
+                );
+                return Ok(LoopExit::Shutdown);
+            }
+            _ = clip_interval.tick(), if shutdown_deadline.is_none() => {
+                if check_and_send_update(read_cmd, session.last_clipboard, stdin).await? {
+                    session.unacked.push_back(None);
+                }
             }
             _ = ping_interval.tick() => {
                 info!("sending ping");
-                send_with_timeout(stdin, Message::Ping).await?;
+                let limits = Some(open.receiver.limits);
+                send_with_timeout(stdin, Message::Ping { limits }).await?;
+                if session.last_heard.elapsed() >= PEER_STALE_AFTER {
+                    open.relay.peer_alive.send_if_modified(|alive| {
+                        std::mem::replace(alive, false)
+                    });
+                }
+                expire_idle_opens(&mut open.receiver, stdin).await?;
+                let pending = open.relay.pending.lock().await;
+                outbox.sent.retain(|(id, _)| pending.contains_key(id));
             }
-            Some(injected) = outbound_rx.recv() => {
-                send_with_timeout(stdin, injected).await?;
+            Some(injected) = outbox.rx.recv() => {
+                let skip = queued_request(&injected)
+                    .is_some_and(|id| outbox.dropped.contains(&id));
+                if !skip {
+                    let sent = &mut outbox.sent;
+                    send_outbound(injected, &mut session, sent, stdin).await?;
+                }
             }
             line_result = reader.next_line() => {
                 match line_result {
                     Ok(Some(line)) => {
+                        session.last_heard = time::Instant::now();
+                        open.relay.peer_alive.send_if_modified(|alive| {
+                            !std::mem::replace(alive, true)
+                        });
                         match serde_json::from_str::<Message>(&line) {
                             Ok(message) => {
                                 dispatch_message(
                                     message,
+                                    read_cmd,
                                     write_cmd,
-                                    &mut last_clipboard,
-                                    &mut last_pong,
-                                    role,
+                                    &mut session,
+                                    open,
                                     stdin,
                                 ).await?;
                             }
@@ -540,6 +1807,13 @@ where
                         }
                     }
                     Ok(None) => {
+                        if shutdown_deadline.is_some() {
+                            info!("peer closed the connection during shutdown");
+                            return Ok(LoopExit::Shutdown);
+                        }
+                        if session.peer_said_bye {
+                            return Ok(LoopExit::PeerClosed);
+                        }
                         return Err("Connection closed".into());
                     }
                     Err(e) => {
@@ -554,152 +1828,426 @@ where
     Err("Pong timeout".into())
 }
 
+/// Sends a message a local socket client queued, or one the last
+/// session left unanswered, noting requests in `sent`.
+async fn send_outbound<W>(
+    msg: Message,
+    session: &mut SessionState<'_>,
+    sent: &mut Vec<(u64, Option<Message>)>,
+    stdin: &mut W,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    W: AsyncWrite + Unpin,
+{
+    match msg {
+        // A `pbcopy` shim on this side set the clipboard through us;
+        // remember it so the poller doesn't echo it back.
+        Message::Clip { clip, request_id } => {
+            *session.last_clipboard = clip.clone();
+            if let Some(id) = request_id {
+                let again = Message::Clip { clip: clip.clone(), request_id };
+                sent.push((id, Some(again)));
+            }
+            let msg = Message::Clip { clip, request_id: None };
+            send_with_timeout(stdin, msg).await?;
+            session.unacked.push_back(request_id);
+        }
+        Message::ClipGet { request_id } => {
+            sent.push((request_id, Some(Message::ClipGet { request_id })));
+            send_with_timeout(stdin, msg).await?;
+        }
+        Message::OpenBegin { request_id, .. } => {
+            sent.push((request_id, None));
+            send_with_timeout(stdin, msg).await?;
+        }
+        msg => send_with_timeout(stdin, msg).await?,
+    }
+    Ok(())
+}
+
+/// The request a queued message carries data for, if any; see
+/// `Outbox::dropped`.
+fn queued_request(msg: &Message) -> Option<u64> {
+    match msg {
+        Message::OpenBegin { request_id, .. }
+        | Message::OpenChunk { request_id, .. }
+        | Message::ClipGet { request_id } => Some(*request_id),
+        _ => None,
+    }
+}
+
 async fn dispatch_message<W>(
     message: Message,
+    read_cmd: &str,
     write_cmd: &str,
-    last_clipboard: &mut String,
-    last_pong: &mut time::Instant,
-    role: &mut OpenRole,
+    session: &mut SessionState<'_>,
+    open: &mut OpenCtx,
     stdin: &mut W,
 ) -> Result<(), Box<dyn std::error::Error>>
 where
     W: AsyncWrite + Unpin,
 {
     match message {
-        Message::Clip { clip } => {
+        Message::Clip { clip, .. } => {
             info!("received clipboard: len={}", clip.len());
-            *last_clipboard = clip.clone();
+            *session.last_clipboard = clip.clone();
             if let Err(e) = set_clipboard(write_cmd, &clip).await {
                 error!("Error setting clipboard: {}", e);
                 return Err(e.into());
             }
+            send_with_timeout(stdin, Message::Ack).await?;
         }
-        Message::Ping => {
+        Message::Ping { limits } => {
             info!("received ping");
+            if let Some(limits) = limits {
+                let mut peer_limits = open.relay.peer_limits.lock().await;
+                if *peer_limits != Some(limits) {
+                    info!("peer limits: {:?}", limits);
+                    *peer_limits = Some(limits);
+                }
+            }
             send_with_timeout(stdin, Message::Pong).await?;
         }
         Message::Pong => {
             info!("received pong");
-            *last_pong = time::Instant::now();
+            session.last_pong = time::Instant::now();
         }
         Message::Ack => {
             info!("received ack");
+            if let Some(Some(request_id)) = session.unacked.pop_front() {
+                route_reply(&open.relay, request_id, Message::Ack).await;
+            }
         }
-        Message::OpenBegin { request_id, files, extra_args } => match role {
-            OpenRole::Receiver(ctx) => {
-                match handle_open_begin(ctx, request_id, files, extra_args)
-                    .await
-                {
-                    Ok(Some(result)) => {
-                        send_with_timeout(stdin, result).await?;
-                    }
-                    Ok(None) => {}
-                    Err(e) => {
-                        warn!("open_begin {} failed: {}", request_id, e);
-                        ctx.states.remove(&request_id);
+        Message::Bye => {
+            // The peer is going away and will close the stream once its
+            // in-flight work drains; keep serving until then.
+            info!("received bye");
+            session.peer_said_bye = true;
+            send_with_timeout(stdin, Message::Ack).await?;
+        }
+        Message::OpenBegin {
+            request_id,
+            name,
+            files,
+            trees,
+            extra_args,
+            dest,
+            follow,
+            update_of,
+            edit,
+        } => {
+            let ctx = &mut open.receiver;
+            let want_have = !files.is_empty();
+            let begin = BeginOpts { dest, follow, update_of, edit };
+            match handle_open_begin(
+                ctx, request_id, &name, files, trees, extra_args, begin,
+            )
+            .await
+            {
+                Ok(Begun::Filling) => {}
+                Ok(Begun::Streaming(indices)) => {
+                    if want_have {
                         send_with_timeout(
                             stdin,
-                            Message::OpenResult {
-                                request_id,
-                                ok: false,
-                                error: Some(e.to_string()),
-                            },
+                            Message::OpenHave { request_id, indices },
                         )
                         .await?;
                     }
                 }
-            }
-            _ => warn!("unexpected OpenBegin on non-receiver role"),
-        },
-        Message::OpenChunk { request_id, index, data_b64, eof } => match role {
-            OpenRole::Receiver(ctx) => {
-                match handle_open_chunk(ctx, request_id, index, &data_b64, eof)
-                    .await
-                {
-                    Ok(Some(result)) => {
-                        send_with_timeout(stdin, result).await?;
-                    }
-                    Ok(None) => {}
-                    Err(e) => {
-                        warn!("open_chunk {} failed: {}", request_id, e);
-                        ctx.states.remove(&request_id);
+                Ok(Begun::Ready(indices, state)) => {
+                    if want_have {
                         send_with_timeout(
                             stdin,
-                            Message::OpenResult {
-                                request_id,
-                                ok: false,
-                                error: Some(e.to_string()),
-                            },
+                            Message::OpenHave { request_id, indices },
                         )
                         .await?;
                     }
+                    spawn_finalize(ctx, request_id, *state);
+                }
+                Err(e) => {
+                    warn!("open_begin {} failed: {}", request_id, e);
+                    ctx.states.remove(&request_id);
+                    send_with_timeout(
+                        stdin,
+                        Message::OpenResult {
+                            request_id,
+                            ok: false,
+                            error: Some(e.to_string()),
+                            paths: Vec::new(),
+                            run: None,
+                        },
+                    )
+                    .await?;
                 }
             }
-            _ => warn!("unexpected OpenChunk on non-receiver role"),
-        },
-        Message::OpenResult { request_id, ok, error } => match role {
-            OpenRole::Relay(ctx) => {
-                let sender_opt = ctx.pending.lock().await.remove(&request_id);
-                if let Some(sender) = sender_opt {
-                    let _ = sender.send(Message::OpenResult {
-                        request_id,
-                        ok,
-                        error,
-                    });
-                } else {
-                    warn!(
-                        "OpenResult for unknown request {} (dropped)",
-                        request_id
-                    );
+        }
+        Message::OpenChunk { request_id, index, data_b64, eof } => {
+            let ctx = &mut open.receiver;
+            match handle_open_chunk(ctx, request_id, index, &data_b64, eof)
+                .await
+            {
+                Ok(Some(state)) => {
+                    spawn_finalize(ctx, request_id, state);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("open_chunk {} failed: {}", request_id, e);
+                    if let Some(state) = ctx.states.remove(&request_id) {
+                        discard_open(request_id, state);
+                    }
+                    send_with_timeout(
+                        stdin,
+                        Message::OpenResult {
+                            request_id,
+                            ok: false,
+                            error: Some(e.to_string()),
+                            paths: Vec::new(),
+                            run: None,
+                        },
+                    )
+                    .await?;
                 }
             }
-            _ => warn!("unexpected OpenResult on non-relay role"),
-        },
+        }
+        Message::OpenUnfollow { request_id } => {
+            let mut follows = open.receiver.follows.lock().await;
+            if follows.remove(&request_id).is_some() {
+                info!("request_id={} no longer followed", request_id);
+            }
+        }
+        Message::OpenCancel { request_id } => {
+            // Too late once all chunks are in: the handler already runs.
+            if let Some(state) = open.receiver.states.remove(&request_id) {
+                info!("request_id={} cancelled by the sender", request_id);
+                discard_open(request_id, state);
+            }
+            // Dropping the receiver tells a running fill to give up.
+            if let Some(mut rx) = open.receiver.filling.remove(&request_id) {
+                info!("request_id={} cancelled by the sender", request_id);
+                if let Ok(state) = rx.try_recv() {
+                    discard_open(request_id, state);
+                }
+            }
+            open.receiver.follows.lock().await.remove(&request_id);
+        }
+        Message::OpenHave { request_id, indices } => {
+            let msg = Message::OpenHave { request_id, indices };
+            forward_reply(&open.relay, request_id, msg).await;
+        }
+        Message::OpenProgress { request_id, bytes } => {
+            let msg = Message::OpenProgress { request_id, bytes };
+            forward_reply(&open.relay, request_id, msg).await;
+        }
+        Message::EditWrite { request_id, data_b64 } => {
+            let msg = Message::EditWrite { request_id, data_b64 };
+            forward_reply(&open.relay, request_id, msg).await;
+        }
+        Message::OpenResult { request_id, ok, error, paths, run } => {
+            let msg = Message::OpenResult { request_id, ok, error, paths, run };
+            route_reply(&open.relay, request_id, msg).await;
+        }
+        Message::PeerWait { .. } | Message::PeerStatus { .. } => {
+            warn!("peer_wait/peer_status from the peer (ignored)");
+        }
+        Message::OpenRestart { request_id } => {
+            warn!("open_restart {} from the peer (ignored)", request_id);
+        }
+        Message::Unknown => {
+            warn!("message of an unknown type from the peer (ignored)");
+        }
+        Message::ClipGet { request_id } => {
+            info!("received clip_get request_id={}", request_id);
+            let clip = match get_clipboard(read_cmd).await {
+                Ok(clip) => clip,
+                Err(e) => {
+                    warn!("clip_get {}: reading clipboard: {}", request_id, e);
+                    String::new()
+                }
+            };
+            send_with_timeout(stdin, Message::ClipValue { request_id, clip })
+                .await?;
+        }
+        Message::ClipValue { request_id, clip } => {
+            let msg = Message::ClipValue { request_id, clip };
+            route_reply(&open.relay, request_id, msg).await;
+        }
     }
     Ok(())
 }
 
+/// Hands a final reply to the socket client waiting on `request_id`.
+async fn route_reply(ctx: &RelayCtx, request_id: u64, msg: Message) {
+    let sender_opt = ctx.pending.lock().await.remove(&request_id);
+    if let Some(sender) = sender_opt {
+        let _ = sender.send(msg);
+    } else {
+        warn!("reply for unknown request {} (dropped)", request_id);
+    }
+}
+
+/// Hands an intermediate reply to the socket client waiting on
+/// `request_id`, leaving the request registered for its final reply.
+async fn forward_reply(ctx: &RelayCtx, request_id: u64, msg: Message) {
+    if let Some(sender) = ctx.pending.lock().await.get(&request_id) {
+        let _ = sender.send(msg);
+    } else {
+        warn!("reply for unknown request {} (dropped)", request_id);
+    }
+}
+
+/// The `open_begin` fields that change what happens to the files rather
+/// than describe them.
+#[derive(Default)]
+struct BeginOpts {
+    dest: Option<String>,
+    follow: bool,
+    update_of: Option<u64>,
+    edit: bool,
+}
+
+/// How far `handle_open_begin` got.
+enum Begun {
+    /// Waiting for chunks; the indices are files the receiver already
+    /// has, for `open_have`.
+    Streaming(Vec<u32>),
+    /// Nothing to stream: the indices, and the finished state.
+    Ready(Vec<u32>, Box<ReceiverState>),
+    /// Cache hits are being copied in by `spawn_cache_fill`, which
+    /// answers the sender itself.
+    Filling,
+}
+
+/// Sets up the request directory for an `open_begin`.
 async fn handle_open_begin(
     ctx: &mut ReceiverCtx,
     request_id: u64,
+    name: &str,
     files: Vec<OpenFileMeta>,
+    trees: Vec<OpenTreeMeta>,
     extra_args: Vec<ArgSlot>,
-) -> Result<Option<Message>, Box<dyn std::error::Error>> {
-    if files.len() > MAX_OPEN_FILES {
-        return Err(format!("too many files: {}", files.len()).into());
+    begin: BeginOpts,
+) -> Result<Begun, Box<dyn std::error::Error>> {
+    let mut handler_name = handler_key(name).to_string();
+    let handler = match begin.dest {
+        Some(dest) if ctx.accept_dest => Arc::new(OpenHandler {
+            cmd: None,
+            allowlist: HashSet::new(),
+            save_to: Some(resolve_dest(&dest)?),
+            trusted: true,
+        }),
+        Some(_) => return Err("destination directories not accepted".into()),
+        None => {
+            let mut handler = ctx.handlers.get(&handler_name);
+            if handler.is_none() && !ctx.strict_handlers {
+                // `xdg-open` and friends predate handler tables.
+                handler_name = "open".to_string();
+                handler = ctx.handlers.get(&handler_name);
+            }
+            handler.cloned().ok_or_else(|| {
+                format!("no open handler configured for {:?}", name)
+            })?
+        }
+    };
+    let max_files = ctx.limits.max_files as usize;
+    if files.len() > max_files {
+        return Err(format!(
+            "too many files: {} (max {})",
+            files.len(),
+            max_files
+        )
+        .into());
     }
-    let total: u64 = files.iter().map(|f| f.size).sum();
-    if total > MAX_OPEN_TOTAL {
-        return Err(format!("total size {} exceeds limit", total).into());
+    let dir_count: usize = trees.iter().map(|t| t.dirs.len() + 1).sum();
+    if dir_count > max_files {
+        return Err(format!(
+            "too many directories: {} (max {})",
+            dir_count, max_files
+        )
+        .into());
     }
-    for f in &files {
-        if f.size > MAX_OPEN_FILE_SIZE {
-            return Err(
-                format!("file {} exceeds per-file limit", f.basename).into()
-            );
-        }
+    // The size limits are part of the policy, which names the rule.
+    ctx.policy
+        .check_sizes(files.iter().map(|f| (f.basename.as_str(), f.size)))?;
+    // Following needs stable local paths: no trees, nothing moved away.
+    if (begin.follow || begin.update_of.is_some())
+        && (!trees.is_empty() || handler.save_to.is_some())
+    {
+        return Err("--follow only works on plain files opened in place".into());
     }
+    // Only the `edit` handler runs an editor: anything else would hand
+    // the file to a command the allowlist was never asked about.
+    if begin.edit && handler_name != "edit" {
+        return Err(format!("{:?} does not handle edit requests", name).into());
+    }
+    if begin.edit
+        && (files.len() != 1
+            || !trees.is_empty()
+            || !matches!(extra_args.as_slice(), [ArgSlot::File { index: 0 }])
+            || begin.follow
+            || handler.cmd.is_none()
+            || handler.save_to.is_some())
+    {
+        return Err("edit takes one file and a handler with a command".into());
+    }
+    let replace = match begin.update_of {
+        Some(id) => {
+            let follows = ctx.follows.lock().await;
+            let followed = follows.get(&id).ok_or_else(|| {
+                format!("request {} is not being followed", id)
+            })?;
+            if followed.len() != files.len() {
+                return Err("update does not match the followed files".into());
+            }
+            // The recorded digests move on in `replace_followed`, once the
+            // new contents are in place.
+            let replace = files
+                .iter()
+                .zip(followed)
+                .map(|(meta, f)| {
+                    let changed =
+                        meta.sha256.is_none() || meta.sha256 != f.sha256;
+                    changed.then(|| f.path.clone())
+                })
+                .collect();
+            Some((id, replace))
+        }
+        None => None,
+    };
 
     // Zero-file request — all positional args were literals (flags, URLs,
     // or paths that didn't stat as a regular file on the remote). Run the
     // local `open` command immediately with the literal args; no files to
     // stream, no target directory needed.
-    if files.is_empty() {
+    if files.is_empty() && trees.is_empty() {
         info!(
-            "open_begin request_id={} files=0 (literal-only, running \
-             immediately)",
-            request_id
+            "open_begin request_id={} handler={} files=0 (literal-only, \
+             running immediately)",
+            request_id,
+            handler_key(name)
         );
         let state = ReceiverState {
+            handler,
+            handler_name: handler_name.clone(),
+            policy: ctx.policy.clone(),
+            confirm: ctx.confirm.clone(),
+            dir: None,
             paths: Vec::new(),
+            mirror: Vec::new(),
+            tree_paths: Vec::new(),
             handles: Vec::new(),
+            hashers: Vec::new(),
+            received: Vec::new(),
+            files,
+            corrupt: Vec::new(),
+            replace: None,
+            follow: None,
             extra_args,
             remaining: 0,
+            progress_at: time::Instant::now(),
+            last_chunk: time::Instant::now(),
+            edit: false,
         };
-        let result =
-            finalize_open(&ctx.allowlist, &ctx.open_cmd, request_id, state)
-                .await;
-        return Ok(Some(result));
+        return Ok(Begun::Ready(Vec::new(), Box::new(state)));
     }
 
     let secs = SystemTime::now()
@@ -708,42 +2256,277 @@ async fn handle_open_begin(
         .unwrap_or(0);
     let rand_suffix =
         Alphanumeric.sample_string(&mut rand::thread_rng(), 6).to_lowercase();
-    let host_dir = ctx.base_dir.join(sanitize_component(&ctx.host));
+    // The remote side has a single peer and no per-host level.
+    let host_dir = if ctx.host.is_empty() {
+        ctx.base_dir.clone()
+    } else {
+        ctx.base_dir.join(sanitize_component(&ctx.host))
+    };
     let dir = host_dir.join(format!("{}-{}", secs, rand_suffix));
 
     std::fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
 
     let mut used: HashSet<String> = HashSet::new();
-    let mut paths = Vec::with_capacity(files.len());
-    let mut handles = Vec::with_capacity(files.len());
-    for meta in &files {
-        let base = sanitize_basename(&meta.basename)
-            .ok_or_else(|| format!("invalid basename: {:?}", meta.basename))?;
+    let mut tree_paths = Vec::with_capacity(trees.len());
+    for tree in &trees {
+        let name = receive_name(&tree.basename, tree.basename_raw.as_deref());
+        let base = sanitize_basename(&name)
+            .ok_or_else(|| format!("invalid basename: {:?}", tree.basename))?;
         let unique = dedupe_name(&used, &base);
         used.insert(unique.clone());
-        let path = dir.join(&unique);
+        let root = dir.join(&unique);
+        std::fs::DirBuilder::new().mode(0o700).create(&root)?;
+        for d in &tree.dirs {
+            let rel = safe_relative_path(d)
+                .ok_or_else(|| format!("invalid directory path: {:?}", d))?;
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(root.join(rel))?;
+        }
+        tree_paths.push(root);
+    }
+
+    let mut paths = Vec::with_capacity(files.len());
+    let mut handles = Vec::with_capacity(files.len());
+    let mut have = Vec::new();
+    let mut fills = Vec::new();
+    let mut mapped = HashSet::new();
+    // A mounted file is used as is, so only where nothing writes to it
+    // or moves it.
+    let use_map = !ctx.path_map.is_empty()
+        && handler.save_to.is_none()
+        && !begin.follow
+        && begin.update_of.is_none()
+        && !begin.edit;
+    for (idx, meta) in files.iter().enumerate() {
+        if let Some(local) =
+            use_map.then(|| mapped_local(&ctx.path_map, meta)).flatten()
+        {
+            info!(
+                "request_id={} {} is mounted at {}; not transferring",
+                request_id,
+                meta.basename,
+                local.display()
+            );
+            have.push(idx as u32);
+            paths.push(local.clone());
+            handles.push(None);
+            mapped.insert(local);
+            continue;
+        }
+        let path = match meta.tree {
+            Some(t) => {
+                let root = tree_paths
+                    .get(t as usize)
+                    .ok_or_else(|| format!("tree index {} out of range", t))?;
+                let rel =
+                    safe_relative_path(&meta.rel_path).ok_or_else(|| {
+                        format!("invalid file path: {:?}", meta.rel_path)
+                    })?;
+                let path = root.join(rel);
+                if let Some(parent) = path.parent() {
+                    std::fs::DirBuilder::new()
+                        .recursive(true)
+                        .mode(0o700)
+                        .create(parent)?;
+                }
+                path
+            }
+            None => {
+                let base = sanitize_basename(&receive_name(
+                    &meta.basename,
+                    meta.basename_raw.as_deref(),
+                ))
+                .ok_or_else(|| {
+                    format!("invalid basename: {:?}", meta.basename)
+                })?;
+                let unique = dedupe_name(&used, &base);
+                used.insert(unique.clone());
+                dir.join(&unique)
+            }
+        };
+        // Everything lives in a fresh request dir, so an existing file
+        // here means the sender listed the same path twice.
         let f = tfs::OpenOptions::new()
-            .create(true)
+            .create_new(true)
             .write(true)
-            .truncate(true)
             .open(&path)
-            .await?;
+            .await
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        if let Some(cached) =
+            ctx.cache_dir.as_deref().and_then(|dir| cached_copy(dir, meta))
+        {
+            fills.push((idx, cached));
+        }
         paths.push(path);
         handles.push(Some(f));
     }
 
-    let remaining = files.len();
-    ctx.states.insert(
-        request_id,
-        ReceiverState { paths, handles, extra_args, remaining },
-    );
+    // Only for files opened where they land; updates of followed files
+    // already know their targets.
+    let mirror =
+        if ctx.mirror && handler.save_to.is_none() && begin.update_of.is_none()
+        {
+            files
+                .iter()
+                .zip(&paths)
+                .map(|(meta, p)| match (&meta.remote_path, meta.tree) {
+                    (Some(remote), None) if !mapped.contains(p) => {
+                        mirror_path(&mirror_dir(&host_dir), remote).map(Some)
+                    }
+                    _ => Ok(None),
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            Vec::new()
+        };
+
+    let remaining = files.len() - have.len();
     info!(
-        "open_begin request_id={} files={} into {}",
+        "open_begin request_id={} handler={} files={} mapped={} cached={} \
+         trees={} into {}",
         request_id,
-        remaining,
+        handler_key(name),
+        files.len(),
+        have.len(),
+        fills.len(),
+        tree_paths.len(),
         dir.display()
     );
-    Ok(None)
+    // Followed only once opened; see `spawn_finalize`.
+    let follow = begin.follow.then(|| {
+        paths
+            .iter()
+            .zip(&files)
+            .enumerate()
+            .map(|(i, (p, meta))| FollowedFile {
+                path: mirror.get(i).cloned().flatten().unwrap_or(p.clone()),
+                sha256: meta.sha256.clone(),
+            })
+            .collect()
+    });
+    let hashers = files.iter().map(|_| Sha256::new()).collect();
+    let received = vec![0; files.len()];
+    let state = ReceiverState {
+        handler,
+        handler_name,
+        policy: ctx.policy.clone(),
+        confirm: ctx.confirm.clone(),
+        dir: Some(dir),
+        paths,
+        mirror,
+        tree_paths,
+        handles,
+        hashers,
+        received,
+        files,
+        corrupt: Vec::new(),
+        replace,
+        follow,
+        extra_args,
+        remaining,
+        progress_at: time::Instant::now(),
+        last_chunk: time::Instant::now(),
+        edit: begin.edit,
+    };
+    if !fills.is_empty() {
+        spawn_cache_fill(ctx, request_id, state, have, fills);
+        return Ok(Begun::Filling);
+    }
+    if remaining == 0 {
+        // Only empty directories or mounted files: nothing to stream.
+        return Ok(Begun::Ready(have, Box::new(state)));
+    }
+    ctx.states.insert(request_id, state);
+    Ok(Begun::Streaming(have))
+}
+
+/// Copies cache hits into a new request off the message loop, since
+/// they can be large. The state goes back to `ctx.filling` before
+/// `open_have` goes out, so it is there again for the first chunk; a
+/// request with nothing left to stream is finished here instead.
+fn spawn_cache_fill(
+    ctx: &mut ReceiverCtx,
+    request_id: u64,
+    state: ReceiverState,
+    mut have: Vec<u32>,
+    fills: Vec<(usize, PathBuf)>,
+) {
+    let (state_tx, state_rx) = oneshot::channel();
+    ctx.filling.insert(request_id, state_rx);
+    let outbound_tx = ctx.outbound_tx.clone();
+    let follows = ctx.follows.clone();
+    let mode_mask = ctx.mode_mask;
+    tokio::spawn(async move {
+        let mut state = state;
+        let mut failed = None;
+        for (idx, cached) in fills {
+            let Some(f) = state.handles[idx].as_mut() else { continue };
+            match fill_from_cache(&cached, f).await {
+                Ok(()) => {
+                    state.handles[idx] = None;
+                    apply_file_meta(
+                        &state.paths[idx],
+                        &state.files[idx],
+                        mode_mask,
+                    );
+                    state.remaining -= 1;
+                    have.push(idx as u32);
+                }
+                // Start over and let the sender stream it.
+                Err(e) => {
+                    warn!("cache read {}: {}", cached.display(), e);
+                    let reset = async {
+                        f.set_len(0).await?;
+                        f.seek(std::io::SeekFrom::Start(0)).await
+                    };
+                    if let Err(e) = reset.await {
+                        failed = Some(format!(
+                            "{}: {}",
+                            state.paths[idx].display(),
+                            e
+                        ));
+                        break;
+                    }
+                }
+            }
+        }
+        // Cancelled, or the connection is gone.
+        if state_tx.is_closed() {
+            discard_open(request_id, state);
+            return;
+        }
+        if let Some(error) = failed {
+            warn!("open_begin {} failed: {}", request_id, error);
+            discard_open(request_id, state);
+            let _ = outbound_tx
+                .send(Message::OpenResult {
+                    request_id,
+                    ok: false,
+                    error: Some(error),
+                    paths: Vec::new(),
+                    run: None,
+                })
+                .await;
+            return;
+        }
+        let ready = if state.remaining == 0 {
+            Some(state)
+        } else {
+            state.last_chunk = time::Instant::now();
+            if let Err(state) = state_tx.send(state) {
+                discard_open(request_id, state);
+                return;
+            }
+            None
+        };
+        let msg = Message::OpenHave { request_id, indices: have };
+        let _ = outbound_tx.send(msg).await;
+        if let Some(state) = ready {
+            finish_open(request_id, state, &outbound_tx, &follows).await;
+        }
+    });
 }
 
 async fn handle_open_chunk(
@@ -752,389 +2535,2597 @@ async fn handle_open_chunk(
     index: u32,
     data_b64: &str,
     eof: bool,
-) -> Result<Option<Message>, Box<dyn std::error::Error>> {
+) -> Result<Option<ReceiverState>, Box<dyn std::error::Error>> {
+    // Always back by now: chunks follow `open_have`.
+    if let Some(rx) = ctx.filling.remove(&request_id) {
+        if let Ok(state) = rx.await {
+            ctx.states.insert(request_id, state);
+        }
+    }
     let state =
         ctx.states.get_mut(&request_id).ok_or("chunk for unknown request")?;
     let idx = index as usize;
     let slot = state.handles.get_mut(idx).ok_or("chunk index out of range")?;
     let f = slot.as_mut().ok_or("chunk for already-closed file")?;
+    state.last_chunk = time::Instant::now();
     if !data_b64.is_empty() {
         let bytes = B64.decode(data_b64)?;
-        f.write_all(&bytes).await?;
+        state.received[idx] += bytes.len() as u64;
+        // Past the announced size the file is bad anyway; don't let it
+        // grow without bound.
+        if state.received[idx] <= state.files[idx].size {
+            f.write_all(&bytes).await?;
+            state.hashers[idx].update(&bytes);
+        }
+        if state.progress_at.elapsed() >= OPEN_PROGRESS_INTERVAL {
+            state.progress_at = time::Instant::now();
+            let bytes = state.received.iter().sum();
+            // This runs on the message loop, which drains the queue, so it
+            // must not wait for room; a skipped update is harmless.
+            let _ = ctx
+                .outbound_tx
+                .try_send(Message::OpenProgress { request_id, bytes });
+        }
     }
     if eof {
         if let Some(mut f) = slot.take() {
             f.flush().await?;
         }
+        let meta = &state.files[idx];
+        let digest = hex_digest(std::mem::take(&mut state.hashers[idx]));
+        let problem = if state.received[idx] != meta.size {
+            Some(format!(
+                "received {} of {} bytes",
+                state.received[idx], meta.size
+            ))
+        } else if meta.sha256.as_ref().is_some_and(|want| *want != digest) {
+            Some("SHA-256 mismatch".to_string())
+        } else {
+            None
+        };
+        let path = &state.paths[idx];
+        if let Some(problem) = problem {
+            warn!(
+                "request_id={} {} failed integrity check: {}",
+                request_id,
+                path.display(),
+                problem
+            );
+            if let Err(e) = tfs::remove_file(path).await {
+                warn!("removing {}: {}", path.display(), e);
+            }
+            state.corrupt.push(format!("{}: {}", meta.basename, problem));
+        } else {
+            // Only content whose hash we computed ourselves goes in the
+            // cache; the sender's claim alone must not be able to poison
+            // it.
+            if let (Some(dir), Some(_)) = (&ctx.cache_dir, &meta.sha256) {
+                if let Err(e) = cache_insert(dir, &digest, path).await {
+                    warn!("cache insert {}: {}", digest, e);
+                }
+            }
+            apply_file_meta(path, meta, ctx.mode_mask);
+        }
         state.remaining -= 1;
         if state.remaining == 0 {
-            let state = ctx.states.remove(&request_id).unwrap();
-            let result =
-                finalize_open(&ctx.allowlist, &ctx.open_cmd, request_id, state)
-                    .await;
-            return Ok(Some(result));
+            return Ok(ctx.states.remove(&request_id));
         }
     }
     Ok(None)
 }
 
-async fn finalize_open(
-    allowlist: &HashSet<String>,
-    open_cmd: &str,
-    request_id: u64,
-    state: ReceiverState,
-) -> Message {
-    for p in &state.paths {
-        let ext_ok = p
-            .extension()
-            .and_then(|s| s.to_str())
-            .map(|s| allowlist.contains(&s.to_lowercase()))
-            .unwrap_or(false);
-        if !ext_ok {
-            let name = p
-                .file_name()
-                .and_then(|s| s.to_str())
-                .unwrap_or("")
-                .to_string();
-            warn!(
-                "request_id={} refusing to open {}: extension not in allowlist",
-                request_id, name
-            );
-            return Message::OpenResult {
-                request_id,
-                ok: false,
-                error: Some(format!("{}: extension not in allowlist", name)),
-            };
+/// Resolves a `push --to` directory: `~/` is expanded and relative paths
+/// are taken from the home directory. It must already exist.
+fn resolve_dest(dest: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut path = expand_home(dest);
+    if path.is_relative() {
+        if let Ok(home) = std::env::var("HOME") {
+            path = PathBuf::from(home).join(path);
         }
     }
+    match std::fs::metadata(&path) {
+        Ok(meta) if meta.is_dir() => Ok(path),
+        Ok(_) => Err(format!("{}: not a directory", path.display()).into()),
+        Err(e) => Err(format!("{}: {}", path.display(), e).into()),
+    }
+}
 
-    let parts = match shlex::split(open_cmd) {
-        Some(p) if !p.is_empty() => p,
-        _ => {
-            return Message::OpenResult {
-                request_id,
-                ok: false,
-                error: Some("invalid local_open_cmd".into()),
-            };
+/// Gives a completed file the sender's mtime and its permission bits
+/// within `mode_mask`. The owner can always read it, since we still have
+/// to. Failures are only logged: the content is what matters.
+fn apply_file_meta(path: &Path, meta: &OpenFileMeta, mode_mask: u32) {
+    if let Some(mtime) = meta.mtime {
+        let time = if mtime >= 0 {
+            UNIX_EPOCH.checked_add(Duration::from_secs(mtime as u64))
+        } else {
+            UNIX_EPOCH.checked_sub(Duration::from_secs(mtime.unsigned_abs()))
+        };
+        let set = time.ok_or_else(|| std::io::ErrorKind::InvalidInput.into());
+        if let Err(e) = set.and_then(|t| {
+            std::fs::File::open(path).and_then(|f| f.set_modified(t))
+        }) {
+            warn!("setting mtime of {}: {}", path.display(), e);
         }
-    };
-    let mut cmd = Command::new(&parts[0]);
-    cmd.args(&parts[1..]);
-    for slot in &state.extra_args {
-        match slot {
-            ArgSlot::Literal { value } => {
-                cmd.arg(value);
-            }
-            ArgSlot::File { index } => match state.paths.get(*index as usize) {
-                Some(p) => {
-                    cmd.arg(p);
-                }
-                None => {
-                    return Message::OpenResult {
-                        request_id,
-                        ok: false,
-                        error: Some(format!("bad file slot index {}", index)),
-                    };
-                }
-            },
+    }
+    if let Some(mode) = meta.mode {
+        let mode = (mode & mode_mask & 0o777) | 0o400;
+        let perms = std::fs::Permissions::from_mode(mode);
+        if let Err(e) = std::fs::set_permissions(path, perms) {
+            warn!("setting mode of {}: {}", path.display(), e);
         }
     }
-    cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
-    let cmd_display = format_command(cmd.as_std());
-    info!("running local open (request_id={}): {}", request_id, cmd_display);
-    match cmd.output().await {
-        Ok(out) => {
-            let code = match out.status.code() {
-                Some(c) => c.to_string(),
-                None => format!("signal ({})", out.status),
-            };
-            let stderr =
-                String::from_utf8_lossy(&out.stderr).trim().to_string();
-            let stdout =
-                String::from_utf8_lossy(&out.stdout).trim().to_string();
-            if out.status.success() {
-                info!(
-                    "local open finished (request_id={}): exit={} stdout={:?} \
-                     stderr={:?}",
-                    request_id, code, stdout, stderr
+}
+
+/// Where received files are cached by content hash, below a host's
+/// directory: `open_have` tells the sender which hashes are present, so a
+/// remote must only learn about content it sent itself.
+fn content_cache_dir(dir: &Path) -> PathBuf {
+    dir.join(".cache").join("sha256")
+}
+
+/// Where `--open-mirror` keeps a host's files, apart from its request
+/// directories and content cache so remote paths can't name either.
+fn mirror_dir(host_dir: &Path) -> PathBuf {
+    host_dir.join("mirror")
+}
+
+fn hex_digest(hasher: Sha256) -> String {
+    hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The cache entry for `meta`, if it claims a well-formed hash and an
+/// entry of the right size exists.
+fn cached_copy(cache_dir: &Path, meta: &OpenFileMeta) -> Option<PathBuf> {
+    let hash = meta.sha256.as_deref()?;
+    if hash.len() != 64
+        || !hash
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    {
+        return None;
+    }
+    let path = cache_dir.join(hash);
+    let entry = std::fs::symlink_metadata(&path).ok()?;
+    (entry.is_file() && entry.len() == meta.size).then_some(path)
+}
+
+async fn fill_from_cache(
+    cached: &Path,
+    dest: &mut tfs::File,
+) -> std::io::Result<()> {
+    let mut src = tfs::File::open(cached).await?;
+    tokio::io::copy(&mut src, dest).await?;
+    dest.flush().await?;
+    // Hits count as use for the retention sweep.
+    src.into_std().await.set_modified(SystemTime::now())?;
+    Ok(())
+}
+
+/// Adds a completed file to the cache under `digest`. Written to a
+/// temporary name and renamed so readers never see a partial entry.
+async fn cache_insert(
+    cache_dir: &Path,
+    digest: &str,
+    src: &Path,
+) -> std::io::Result<()> {
+    let dest = cache_dir.join(digest);
+    if tfs::symlink_metadata(&dest).await.is_ok() {
+        return Ok(());
+    }
+    std::fs::DirBuilder::new().recursive(true).mode(0o700).create(cache_dir)?;
+    let rand_suffix =
+        Alphanumeric.sample_string(&mut rand::thread_rng(), 6).to_lowercase();
+    let tmp = cache_dir.join(format!(".{}.{}", digest, rand_suffix));
+    if let Err(e) = tfs::copy(src, &tmp).await {
+        let _ = tfs::remove_file(&tmp).await;
+        return Err(e);
+    }
+    tfs::rename(&tmp, &dest).await
+}
+
+/// Validates a `/`-separated path from the sender for use below a tree
+/// root. Unlike `sanitize_basename`, which reduces a single name, this
+/// rejects outright anything that could climb out: absolute paths, `.`,
+/// `..` or empty components, NULs, and absurd depth or lengths.
+fn safe_relative_path(s: &str) -> Option<PathBuf> {
+    if s.is_empty() || s.len() > 4096 {
+        return None;
+    }
+    let mut out = PathBuf::new();
+    for (depth, comp) in s.split('/').enumerate() {
+        if depth >= 64
+            || comp.is_empty()
+            || comp == "."
+            || comp == ".."
+            || comp.contains('\0')
+            || comp.len() > 255
+        {
+            return None;
+        }
+        out.push(comp);
+    }
+    Some(out)
+}
+
+/// Parses `--open-path-map`.
+fn parse_path_map(
+    csv: &str,
+) -> Result<Vec<(String, PathBuf)>, Box<dyn std::error::Error>> {
+    let mut map = Vec::new();
+    for entry in csv.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (remote, local) = entry
+            .split_once('=')
+            .filter(|(r, l)| r.starts_with('/') && !l.is_empty())
+            .ok_or_else(|| {
+                format!(
+                    "--open-path-map: {:?} is not /REMOTE/PREFIX=LOCAL",
+                    entry
+                )
+            })?;
+        map.push((
+            remote.trim_end_matches('/').to_string(),
+            expand_home(local),
+        ));
+    }
+    map.sort_by_key(|(remote, _)| std::cmp::Reverse(remote.len()));
+    Ok(map)
+}
+
+/// The local path `--open-path-map` gives the sender's file, if the file
+/// there has the same size and mtime — the same file seen through a
+/// mount, as far as we can tell without reading it. As with `mirror_path`,
+/// nothing below the mapped prefix may be a symlink, so the sender can't
+/// point the Mac at a file outside the mount.
+fn mapped_local(
+    map: &[(String, PathBuf)],
+    meta: &OpenFileMeta,
+) -> Option<PathBuf> {
+    let remote = meta.remote_path.as_deref()?;
+    let mtime = meta.mtime?;
+    let (root, rel) = map.iter().find_map(|(prefix, local)| {
+        let rest = remote.strip_prefix(prefix.as_str())?.strip_prefix('/')?;
+        Some((local, safe_relative_path(rest)?))
+    })?;
+    let mut local = root.clone();
+    for comp in rel.components() {
+        local.push(comp);
+        if std::fs::symlink_metadata(&local).ok()?.file_type().is_symlink() {
+            return None;
+        }
+    }
+    let local = std::fs::canonicalize(&local).ok()?;
+    if !local.starts_with(std::fs::canonicalize(root).ok()?) {
+        return None;
+    }
+    let found = std::fs::metadata(&local).ok()?;
+    (found.is_file() && found.len() == meta.size && found.mtime() == mtime)
+        .then_some(local)
+}
+
+/// Where `--open-mirror` keeps the file the sender has at `remote_path`:
+/// the same path below `root` (see `mirror_dir`), whose directories are
+/// created here. Only plain absolute paths pass, and nothing on the way
+/// may be a symlink or the target anything but a regular file, so the
+/// result stays inside `root`.
+fn mirror_path(
+    root: &Path,
+    remote_path: &str,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let rel = remote_path
+        .strip_prefix('/')
+        .and_then(safe_relative_path)
+        .ok_or_else(|| format!("invalid remote path: {:?}", remote_path))?;
+    std::fs::DirBuilder::new().recursive(true).mode(0o700).create(root)?;
+    if std::fs::symlink_metadata(root)?.file_type().is_symlink() {
+        return Err(format!("{}: is a symlink", root.display()).into());
+    }
+    let mut path = root.to_path_buf();
+    let mut comps = rel.components().peekable();
+    while let Some(comp) = comps.next() {
+        path.push(comp);
+        let last = comps.peek().is_none();
+        match std::fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                return Err(format!("{}: is a symlink", path.display()).into());
+            }
+            Ok(meta) if !last && !meta.is_dir() => {
+                return Err(
+                    format!("{}: not a directory", path.display()).into()
                 );
-                Message::OpenResult { request_id, ok: true, error: None }
-            } else {
-                warn!(
-                    "local open failed (request_id={}): exit={} stdout={:?} \
-                     stderr={:?}",
-                    request_id, code, stdout, stderr
+            }
+            Ok(meta) if last && !meta.is_file() => {
+                return Err(
+                    format!("{}: not a regular file", path.display()).into()
                 );
-                Message::OpenResult {
-                    request_id,
-                    ok: false,
-                    error: Some(format!("open exit {}: {}", out.status, stderr)),
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                if !last {
+                    std::fs::DirBuilder::new().mode(0o700).create(&path)?;
                 }
             }
+            Err(e) => return Err(format!("{}: {}", path.display(), e).into()),
         }
-        Err(e) => {
-            warn!(
-                "local open spawn failed (request_id={}): {}",
-                request_id, e
-            );
+    }
+    Ok(path)
+}
+
+/// Renames complete files over their mirror paths, so an app showing the
+/// previous copy sees the new one replace it at once. Only for files that
+/// passed every check: a refused one must not replace a good copy. The
+/// request directory goes if that emptied it.
+fn move_to_mirror(state: &mut ReceiverState) -> Result<(), String> {
+    if !state.corrupt.is_empty() {
+        return Ok(());
+    }
+    for (path, target) in state.paths.iter_mut().zip(&state.mirror) {
+        let Some(target) = target else { continue };
+        std::fs::rename(&*path, target)
+            .map_err(|e| format!("{}: {}", target.display(), e))?;
+        info!("mirrored {}", target.display());
+        *path = target.clone();
+    }
+    // Empty now if every file was mirrored or found mounted.
+    if state.dir.as_ref().is_some_and(|d| std::fs::remove_dir(d).is_ok()) {
+        state.dir = None;
+    }
+    Ok(())
+}
+
+fn mirror_failed(request_id: u64, e: String) -> Message {
+    Message::OpenResult {
+        request_id,
+        ok: false,
+        error: Some(format!("mirroring: {}", e)),
+        paths: Vec::new(),
+        run: None,
+    }
+}
+
+/// Drops a request that will never complete: closes its files and deletes
+/// its request directory with everything written so far.
+fn discard_open(request_id: u64, state: ReceiverState) {
+    drop(state.handles);
+    if let Some(dir) = &state.dir {
+        match std::fs::remove_dir_all(dir) {
+            Ok(()) => info!(
+                "request_id={} removed partial files in {}",
+                request_id,
+                dir.display()
+            ),
+            Err(e) => warn!("removing {}: {}", dir.display(), e),
+        }
+    }
+}
+
+/// Fails and discards requests whose sender stopped sending chunks without
+/// a cancel, e.g. because the connection to it hung.
+async fn expire_idle_opens<W>(
+    ctx: &mut ReceiverCtx,
+    stdin: &mut W,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    W: AsyncWrite + Unpin,
+{
+    ctx.settle_fills();
+    let idle: Vec<u64> = ctx
+        .states
+        .iter()
+        .filter(|(_, s)| s.last_chunk.elapsed() >= OPEN_IDLE_TIMEOUT)
+        .map(|(id, _)| *id)
+        .collect();
+    for request_id in idle {
+        let Some(state) = ctx.states.remove(&request_id) else { continue };
+        warn!(
+            "request_id={} abandoned: no data for {:?}",
+            request_id, OPEN_IDLE_TIMEOUT
+        );
+        discard_open(request_id, state);
+        let error = format!(
+            "transfer abandoned: no data for {}s",
+            OPEN_IDLE_TIMEOUT.as_secs()
+        );
+        send_with_timeout(
+            stdin,
             Message::OpenResult {
                 request_id,
                 ok: false,
-                error: Some(format!("spawn failed: {}", e)),
+                error: Some(error),
+                paths: Vec::new(),
+                run: None,
+            },
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Runs `finalize_open` off the message loop — handler commands such as
+/// `code --wait` can block for as long as the user keeps the window
+/// open — and sends the result back through the outbound channel.
+fn spawn_finalize(ctx: &ReceiverCtx, request_id: u64, state: ReceiverState) {
+    let outbound_tx = ctx.outbound_tx.clone();
+    let follows = ctx.follows.clone();
+    tokio::spawn(async move {
+        finish_open(request_id, state, &outbound_tx, &follows).await;
+    });
+}
+
+async fn finish_open(
+    request_id: u64,
+    mut state: ReceiverState,
+    outbound_tx: &mpsc::Sender<Message>,
+    follows: &Mutex<HashMap<u64, Vec<FollowedFile>>>,
+) {
+    let follow = state.follow.take();
+    let result = if !state.edit {
+        finalize_open(request_id, state, follows).await
+    } else {
+        edit_session(request_id, state, outbound_tx).await
+    };
+    if let (Some(followed), Message::OpenResult { ok: true, .. }) =
+        (follow, &result)
+    {
+        follows.lock().await.insert(request_id, followed);
+    }
+    if outbound_tx.send(result).await.is_err() {
+        warn!(
+            "request_id={} finished after the connection closed; result \
+             dropped",
+            request_id
+        );
+    }
+}
+
+/// `clipcast edit`: runs the editor on the received file and, until it
+/// exits, sends each save back as `edit_write` once the file has stayed
+/// unchanged for a poll interval. The result follows the last write.
+async fn edit_session(
+    request_id: u64,
+    mut state: ReceiverState,
+    outbound_tx: &mpsc::Sender<Message>,
+) -> Message {
+    let fail = |error: String| Message::OpenResult {
+        request_id,
+        ok: false,
+        error: Some(error),
+        paths: Vec::new(),
+        run: None,
+    };
+    if !state.corrupt.is_empty() {
+        return fail(format!(
+            "integrity check failed, not opened: {}",
+            state.corrupt.join("; ")
+        ));
+    }
+    // The editor opens the file like any handler would.
+    match judge_request(request_id, &state).await {
+        Ok(skip) if skip.is_empty() => {}
+        Ok(_) => {
+            return fail("the policy only saves files of this type".into())
+        }
+        Err(why) => return fail(why),
+    }
+    if let Err(e) = move_to_mirror(&mut state) {
+        return mirror_failed(request_id, e);
+    }
+    let (Some(editor), Some(path)) =
+        (state.handler.cmd.as_deref(), state.paths.first())
+    else {
+        return fail("nothing to edit".into());
+    };
+    let parts = match shlex::split(editor) {
+        Some(p) if !p.is_empty() => p,
+        _ => return fail(format!("invalid edit command {:?}", editor)),
+    };
+    let mut cmd = Command::new(&parts[0]);
+    cmd.args(&parts[1..]).arg(path);
+    cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    info!(
+        "running editor (request_id={}): {}",
+        request_id,
+        format_command(cmd.as_std())
+    );
+    let child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => return fail(format!("spawn failed: {}", e)),
+    };
+
+    let stat = || {
+        let meta = std::fs::metadata(path).ok()?;
+        Some((meta.len(), meta.modified().ok()?))
+    };
+    let mut sent = stat();
+    let mut seen = sent;
+    let mut poll = time::interval(FOLLOW_POLL_INTERVAL);
+    let wait = child.wait_with_output();
+    tokio::pin!(wait);
+    let output = loop {
+        tokio::select! {
+            out = &mut wait => break out,
+            _ = poll.tick() => {
+                let now = stat();
+                if now.is_some() && now != sent && now == seen {
+                    send_edit_write(request_id, path, outbound_tx).await;
+                    sent = now;
+                }
+                seen = now;
             }
         }
+    };
+    // A save just before the editor quit had no time to settle.
+    if stat().is_some() && stat() != sent {
+        send_edit_write(request_id, path, outbound_tx).await;
     }
-}
 
-fn format_command(cmd: &std::process::Command) -> String {
-    let mut parts: Vec<String> = Vec::new();
-    parts.push(cmd.get_program().to_string_lossy().into_owned());
-    for a in cmd.get_args() {
-        parts.push(a.to_string_lossy().into_owned());
+    let out = match output {
+        Ok(out) => out,
+        Err(e) => return fail(format!("waiting for the editor: {}", e)),
+    };
+    info!("editor exited (request_id={}): {}", request_id, out.status);
+    let run = OpenRun {
+        exit_code: out.status.code(),
+        signal: std::os::unix::process::ExitStatusExt::signal(&out.status),
+        stdout: truncate_output(&out.stdout),
+        stderr: truncate_output(&out.stderr),
+        opened: vec![path.display().to_string()],
+    };
+    Message::OpenResult {
+        request_id,
+        ok: out.status.success(),
+        error: (!out.status.success())
+            .then(|| format!("{} failed ({})", parts[0], out.status)),
+        paths: Vec::new(),
+        run: Some(run),
     }
-    parts
-        .iter()
-        .map(|s| match shlex::try_quote(s) {
-            Ok(q) => q.into_owned(),
-            Err(_) => format!("{:?}", s),
-        })
-        .collect::<Vec<_>>()
-        .join(" ")
 }
 
-async fn handle_socket_client(
-    stream: UnixStream,
-    outbound_tx: mpsc::UnboundedSender<Message>,
-    pending: Arc<Mutex<HashMap<u64, mpsc::UnboundedSender<Message>>>>,
+async fn send_edit_write(
+    request_id: u64,
+    path: &Path,
+    outbound_tx: &mpsc::Sender<Message>,
 ) {
-    let (reader, mut writer) = stream.into_split();
-    let mut lines = BufReader::new(reader).lines();
-    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel::<Message>();
-    let mut req_id: Option<u64> = None;
+    match tfs::read(path).await {
+        Ok(data) => {
+            info!(
+                "request_id={} sending save of {} ({} bytes)",
+                request_id,
+                path.display(),
+                data.len()
+            );
+            let data_b64 = B64.encode(&data);
+            let msg = Message::EditWrite { request_id, data_b64 };
+            let _ = outbound_tx.send(msg).await;
+        }
+        Err(e) => warn!("reading {}: {}", path.display(), e),
+    }
+}
 
-    loop {
-        tokio::select! {
-            line = lines.next_line() => {
-                match line {
-                    Ok(Some(l)) => {
-                        match serde_json::from_str::<Message>(&l) {
-                            Ok(msg) => {
-                                if req_id.is_none() {
-                                    if let Message::OpenBegin { request_id, .. } = &msg {
-                                        req_id = Some(*request_id);
-                                        pending
-                                            .lock()
-                                            .await
-                                            .insert(*request_id, reply_tx.clone());
-                                    }
-                                }
-                                if outbound_tx.send(msg).is_err() {
-                                    warn!("outbound channel closed");
-                                    break;
-                                }
-                            }
-                            Err(e) => {
-                                error!("socket parse error: {}", e);
-                                break;
-                            }
+/// Judges the file and directory arguments of a request by name, asks
+/// the confirmation prompt where set, then sniffs the files to be opened.
+/// Returns the argument slots to leave out of the command (saved only),
+/// or why the request is refused.
+async fn judge_request(
+    request_id: u64,
+    state: &ReceiverState,
+) -> Result<HashSet<usize>, String> {
+    // Judge every file and directory argument by name first.
+    let mut judged: Vec<(usize, &PathBuf, String, bool, Verdict)> = Vec::new();
+    for (i, slot) in state.extra_args.iter().enumerate() {
+        let (p, is_dir) = match slot {
+            ArgSlot::Literal { .. } => continue,
+            ArgSlot::File { index } => {
+                (state.paths.get(*index as usize), false)
+            }
+            ArgSlot::Tree { index } => {
+                (state.tree_paths.get(*index as usize), true)
+            }
+        };
+        // Bad indices are reported when the command is assembled.
+        let Some(p) = p else { continue };
+        let name =
+            p.file_name().and_then(|s| s.to_str()).unwrap_or("").to_string();
+        let verdict =
+            state.policy.judge_name(&name, is_dir, &state.handler.allowlist);
+        judged.push((i, p, name, is_dir, verdict));
+    }
+
+    // Then ask about refusals by name, and about extensions this host
+    // never sent before, if a prompt is set.
+    if let Some(confirm) = &state.confirm {
+        let mut asking = Vec::new();
+        for (k, (_, p, name, is_dir, verdict)) in judged.iter_mut().enumerate()
+        {
+            let ext = extension_of(name);
+            match verdict {
+                Verdict::Refuse(why) => {
+                    let always = ext.as_deref().is_some_and(|e| {
+                        confirm.is_always_allowed(&state.handler_name, e)
+                    });
+                    if always {
+                        *verdict = Verdict::Open;
+                        continue;
+                    }
+                    if !confirm::may_ask(name, *is_dir, p) {
+                        why.push_str("; not offered for confirmation");
+                        continue;
+                    }
+                }
+                Verdict::Open => match &ext {
+                    Some(e) if !confirm.has_seen(e) => {
+                        if !confirm::may_ask(name, *is_dir, p) {
+                            continue;
                         }
                     }
-                    Ok(None) => break,
-                    Err(e) => {
-                        error!("socket read error: {}", e);
-                        break;
+                    _ => continue,
+                },
+                Verdict::SaveOnly => continue,
+            }
+            let size = if *is_dir {
+                state
+                    .paths
+                    .iter()
+                    .filter(|f| f.starts_with(p.as_path()))
+                    .filter_map(|f| std::fs::metadata(f).ok())
+                    .map(|m| m.len())
+                    .sum()
+            } else {
+                std::fs::metadata(p.as_path()).map(|m| m.len()).unwrap_or(0)
+            };
+            asking.push((
+                k,
+                ext,
+                confirm::Candidate {
+                    name: name.clone(),
+                    size,
+                    is_dir: *is_dir,
+                },
+            ));
+        }
+        if !asking.is_empty() {
+            let (slots, candidates): (Vec<_>, Vec<_>) =
+                asking.into_iter().map(|(k, ext, c)| ((k, ext), c)).unzip();
+            let decision = confirm.ask(&state.handler_name, &candidates).await;
+            let mut allowed = Vec::new();
+            let mut refused_exts = Vec::new();
+            for (k, ext) in slots {
+                let (_, _, name, _, verdict) = &mut judged[k];
+                match decision {
+                    Decision::AllowOnce | Decision::AllowAlways => {
+                        if let Verdict::Refuse(_) = verdict {
+                            refused_exts.extend(ext.clone());
+                        }
+                        *verdict = Verdict::Open;
+                        allowed.extend(ext);
+                    }
+                    Decision::Deny => match verdict {
+                        Verdict::Refuse(why) => {
+                            why.push_str("; denied at the prompt")
+                        }
+                        _ => {
+                            *verdict = Verdict::Refuse(format!(
+                                "{}: first file of its kind from this host; \
+                                 denied at the prompt",
+                                name
+                            ))
+                        }
+                    },
+                }
+            }
+            if decision == Decision::AllowAlways && !refused_exts.is_empty() {
+                refused_exts.sort();
+                refused_exts.dedup();
+                confirm.allow_always(
+                    &state.handler_name,
+                    state.handler.cmd.as_deref(),
+                    &refused_exts,
+                );
+            }
+            confirm.mark_seen(allowed);
+        }
+    }
+
+    let mut refused = Vec::new();
+    let mut skip: HashSet<usize> = HashSet::new();
+    for (i, p, name, is_dir, mut verdict) in judged {
+        if verdict == Verdict::Open && !is_dir {
+            // Sniffing can misjudge a file, so it is kept, just not opened.
+            if let Err(why) = state.policy.check_content(&name, p) {
+                verdict = Verdict::Refuse(why);
+            }
+        }
+        match verdict {
+            Verdict::Open => {}
+            Verdict::SaveOnly => {
+                info!(
+                    "request_id={} saved without opening {}",
+                    request_id, name
+                );
+                skip.insert(i);
+            }
+            Verdict::Refuse(why) => {
+                warn!("request_id={} refusing to open {}", request_id, why);
+                refused.push(why);
+            }
+        }
+    }
+    if !refused.is_empty() {
+        return Err(refused.join("; "));
+    }
+    Ok(skip)
+}
+
+async fn finalize_open(
+    request_id: u64,
+    mut state: ReceiverState,
+    follows: &Mutex<HashMap<u64, Vec<FollowedFile>>>,
+) -> Message {
+    if !state.corrupt.is_empty() {
+        return Message::OpenResult {
+            request_id,
+            ok: false,
+            error: Some(format!(
+                "integrity check failed, not opened: {}",
+                state.corrupt.join("; ")
+            )),
+            paths: Vec::new(),
+            run: None,
+        };
+    }
+    if let Some((id, replace)) = state.replace.take() {
+        let mut follows = follows.lock().await;
+        let followed = follows.get_mut(&id).map(Vec::as_mut_slice);
+        return replace_followed(request_id, state, replace, followed);
+    }
+    let handler = state.handler.clone();
+    // Before anything moves: a refused file must not land in `save_to`.
+    let skip = if handler.trusted {
+        HashSet::new()
+    } else {
+        match judge_request(request_id, &state).await {
+            Ok(skip) => skip,
+            Err(why) => {
+                return Message::OpenResult {
+                    request_id,
+                    ok: false,
+                    error: Some(why),
+                    paths: Vec::new(),
+                    run: None,
+                };
+            }
+        }
+    };
+    if let Some(save_to) = &handler.save_to {
+        // Move the top-level entries (plain files and tree roots) holding
+        // the judged arguments; paths of files inside trees follow their
+        // root.
+        let targets: Vec<&PathBuf> = state
+            .extra_args
+            .iter()
+            .filter_map(|slot| match slot {
+                ArgSlot::File { index } => state.paths.get(*index as usize),
+                ArgSlot::Tree { index } => {
+                    state.tree_paths.get(*index as usize)
+                }
+                ArgSlot::Literal { .. } => None,
+            })
+            .collect();
+        let top: Vec<PathBuf> = state
+            .paths
+            .iter()
+            .filter(|p| p.parent() == state.dir.as_deref())
+            .chain(state.tree_paths.iter())
+            .filter(|p| targets.iter().any(|t| t.starts_with(p)))
+            .cloned()
+            .collect();
+        let moving = (top.clone(), save_to.clone());
+        let moved = tokio::task::spawn_blocking(move || {
+            move_into(&moving.0, &moving.1).map_err(|e| e.to_string())
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+        match moved {
+            Ok(moved) => {
+                if let Some(dir) = state.dir.take() {
+                    let _ = std::fs::remove_dir(dir);
+                }
+                for p in &moved {
+                    info!("request_id={} saved {}", request_id, p.display());
+                }
+                let remap = |p: &PathBuf| {
+                    for (old, new) in top.iter().zip(&moved) {
+                        // `join("")` would add a trailing slash.
+                        if p == old {
+                            return new.clone();
+                        }
+                        if let Ok(rest) = p.strip_prefix(old) {
+                            return new.join(rest);
+                        }
                     }
+                    p.clone()
+                };
+                state.paths = state.paths.iter().map(remap).collect();
+                state.tree_paths = state.tree_paths.iter().map(remap).collect();
+            }
+            Err(e) => {
+                warn!(
+                    "request_id={} saving into {} failed: {}",
+                    request_id,
+                    save_to.display(),
+                    e
+                );
+                return Message::OpenResult {
+                    request_id,
+                    ok: false,
+                    error: Some(format!(
+                        "saving into {}: {}",
+                        save_to.display(),
+                        e
+                    )),
+                    paths: Vec::new(),
+                    run: None,
+                };
+            }
+        }
+    }
+
+    let open_cmd = match &handler.cmd {
+        Some(cmd) => cmd,
+        None => {
+            info!(
+                "request_id={} handler has no command; files saved only",
+                request_id
+            );
+            if let Err(e) = move_to_mirror(&mut state) {
+                return mirror_failed(request_id, e);
+            }
+            let top = state
+                .paths
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| state.files[*i].tree.is_none())
+                .map(|(_, p)| p)
+                .chain(state.tree_paths.iter());
+            let paths = top.map(|p| p.display().to_string()).collect();
+            return Message::OpenResult {
+                request_id,
+                ok: true,
+                error: None,
+                paths,
+                run: None,
+            };
+        }
+    };
+
+    if let Err(e) = move_to_mirror(&mut state) {
+        return mirror_failed(request_id, e);
+    }
+    let has_targets = state.extra_args.iter().enumerate().any(|(i, slot)| {
+        !matches!(slot, ArgSlot::Literal { .. }) && !skip.contains(&i)
+    });
+    // Where the saved-only files ended up, now that they are in place.
+    let saved_only: Vec<String> = state
+        .extra_args
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| skip.contains(i))
+        .filter_map(|(_, slot)| match slot {
+            ArgSlot::File { index } => state.paths.get(*index as usize),
+            ArgSlot::Tree { index } => state.tree_paths.get(*index as usize),
+            ArgSlot::Literal { .. } => None,
+        })
+        .map(|p| p.display().to_string())
+        .collect();
+    if !skip.is_empty() && !has_targets {
+        return Message::OpenResult {
+            request_id,
+            ok: true,
+            error: None,
+            paths: saved_only,
+            run: None,
+        };
+    }
+
+    let parts = match shlex::split(open_cmd) {
+        Some(p) if !p.is_empty() => p,
+        _ => {
+            return Message::OpenResult {
+                request_id,
+                ok: false,
+                error: Some(format!("invalid open command {:?}", open_cmd)),
+                paths: Vec::new(),
+                run: None,
+            };
+        }
+    };
+    let mut cmd = Command::new(&parts[0]);
+    cmd.args(&parts[1..]);
+    let mut opened = Vec::new();
+    for (i, slot) in state.extra_args.iter().enumerate() {
+        if skip.contains(&i) {
+            continue;
+        }
+        match slot {
+            ArgSlot::Literal { value, value_raw } => {
+                match value_raw.as_deref().and_then(|b| B64.decode(b).ok()) {
+                    Some(raw) => cmd.arg(OsString::from_vec(raw)),
+                    None => cmd.arg(value),
+                };
+            }
+            ArgSlot::File { index } => match state.paths.get(*index as usize) {
+                Some(p) => {
+                    cmd.arg(p);
+                    opened.push(p.display().to_string());
+                }
+                None => {
+                    return Message::OpenResult {
+                        request_id,
+                        ok: false,
+                        error: Some(format!("bad file slot index {}", index)),
+                        paths: Vec::new(),
+                        run: None,
+                    };
+                }
+            },
+            ArgSlot::Tree { index } => {
+                match state.tree_paths.get(*index as usize) {
+                    Some(p) => {
+                        cmd.arg(p);
+                        opened.push(p.display().to_string());
+                    }
+                    None => {
+                        return Message::OpenResult {
+                            request_id,
+                            ok: false,
+                            error: Some(format!(
+                                "bad tree slot index {}",
+                                index
+                            )),
+                            paths: Vec::new(),
+                            run: None,
+                        };
+                    }
+                }
+            }
+        }
+    }
+    cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    let cmd_display = format_command(cmd.as_std());
+    info!("running local open (request_id={}): {}", request_id, cmd_display);
+    match cmd.output().await {
+        Ok(out) => {
+            let code = match out.status.code() {
+                Some(c) => c.to_string(),
+                None => format!("signal ({})", out.status),
+            };
+            let stderr =
+                String::from_utf8_lossy(&out.stderr).trim().to_string();
+            let stdout =
+                String::from_utf8_lossy(&out.stdout).trim().to_string();
+            let run = OpenRun {
+                exit_code: out.status.code(),
+                signal: std::os::unix::process::ExitStatusExt::signal(
+                    &out.status,
+                ),
+                stdout: truncate_output(&out.stdout),
+                stderr: truncate_output(&out.stderr),
+                opened,
+            };
+            if out.status.success() {
+                info!(
+                    "local open finished (request_id={}): exit={} stdout={:?} \
+                     stderr={:?}",
+                    request_id, code, stdout, stderr
+                );
+                Message::OpenResult {
+                    request_id,
+                    ok: true,
+                    error: None,
+                    paths: saved_only,
+                    run: Some(run),
+                }
+            } else {
+                warn!(
+                    "local open failed (request_id={}): exit={} stdout={:?} \
+                     stderr={:?}",
+                    request_id, code, stdout, stderr
+                );
+                // The output travels in `run`.
+                Message::OpenResult {
+                    request_id,
+                    ok: false,
+                    error: Some(format!(
+                        "{} failed ({})",
+                        parts[0], out.status
+                    )),
+                    paths: Vec::new(),
+                    run: Some(run),
+                }
+            }
+        }
+        Err(e) => {
+            warn!(
+                "local open spawn failed (request_id={}): {}",
+                request_id, e
+            );
+            Message::OpenResult {
+                request_id,
+                ok: false,
+                error: Some(format!("spawn failed: {}", e)),
+                paths: Vec::new(),
+                run: None,
+            }
+        }
+    }
+}
+
+/// Command output for `OpenRun`, cut to `OPEN_OUTPUT_MAX` bytes on a
+/// character boundary.
+fn truncate_output(bytes: &[u8]) -> String {
+    let text = String::from_utf8_lossy(bytes);
+    if text.len() <= OPEN_OUTPUT_MAX {
+        return text.into_owned();
+    }
+    let mut end = OPEN_OUTPUT_MAX;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}\n[{} more bytes truncated]\n", &text[..end], text.len() - end)
+}
+
+/// Finishes an `open --follow` update: renames the changed files over the
+/// local copies the app has open, so it sees each one change at once,
+/// and drops the rest of the staging directory. `followed` (unless the
+/// sender stopped following meanwhile) records each new digest.
+fn replace_followed(
+    request_id: u64,
+    state: ReceiverState,
+    replace: Vec<Option<PathBuf>>,
+    mut followed: Option<&mut [FollowedFile]>,
+) -> Message {
+    let mut replaced = Vec::new();
+    let mut error = None;
+    for (i, (src, target)) in state.paths.iter().zip(replace).enumerate() {
+        let Some(target) = target else { continue };
+        match std::fs::rename(src, &target) {
+            Ok(()) => {
+                info!("request_id={} updated {}", request_id, target.display());
+                if let Some(f) =
+                    followed.as_deref_mut().and_then(|f| f.get_mut(i))
+                {
+                    f.sha256 = state.files[i].sha256.clone();
+                }
+                replaced.push(target.display().to_string());
+            }
+            Err(e) => {
+                warn!("updating {}: {}", target.display(), e);
+                error = Some(format!("updating {}: {}", target.display(), e));
+                break;
+            }
+        }
+    }
+    if let Some(dir) = &state.dir {
+        let _ = std::fs::remove_dir_all(dir);
+    }
+    Message::OpenResult {
+        request_id,
+        ok: error.is_none(),
+        error,
+        paths: replaced,
+        run: None,
+    }
+}
+
+/// Whether `open` bundles `path` unless told otherwise.
+fn is_html(path: &Path) -> bool {
+    path.extension().is_some_and(|e| {
+        e.eq_ignore_ascii_case("html") || e.eq_ignore_ascii_case("htm")
+    })
+}
+
+/// Lowercased extension of a file name, if it has one.
+fn extension_of(name: &str) -> Option<String> {
+    Path::new(name)
+        .extension()
+        .and_then(|s| s.to_str())
+        .map(|s| s.to_lowercase())
+}
+
+/// Handler table key for a shim name; senders that predate the name
+/// field are plain `open`.
+fn handler_key(name: &str) -> &str {
+    if name.is_empty() {
+        "open"
+    } else {
+        name
+    }
+}
+
+/// Moves received files into `dir` (created if missing), renaming around
+/// anything already there. Each name is claimed by creating it, so
+/// concurrent requests and other writers can't take it in between; the
+/// move then replaces that placeholder. Falls back to copy + remove when
+/// `dir` is on another filesystem. Blocking; call it from
+/// `spawn_blocking`.
+fn move_into(
+    paths: &[PathBuf],
+    dir: &Path,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    std::fs::create_dir_all(dir)?;
+    // Only a hint at which names are free.
+    let mut used: HashSet<String> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().into_string().ok())
+        .collect();
+    let mut moved = Vec::with_capacity(paths.len());
+    for p in paths {
+        let name = p
+            .file_name()
+            .and_then(|s| s.to_str())
+            .ok_or("bad file name")?
+            .to_string();
+        let is_dir = p.symlink_metadata()?.is_dir();
+        let dest = loop {
+            let unique = dedupe_name(&used, &name);
+            used.insert(unique.clone());
+            let dest = dir.join(&unique);
+            let claimed = if is_dir {
+                std::fs::DirBuilder::new().mode(0o700).create(&dest)
+            } else {
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(&dest)
+                    .map(drop)
+            };
+            match claimed {
+                Ok(()) => break dest,
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(e) => {
+                    return Err(format!("{}: {}", dest.display(), e).into())
+                }
+            }
+        };
+        if std::fs::rename(p, &dest).is_err() {
+            let copied = if is_dir {
+                std::fs::read_dir(p).and_then(|entries| {
+                    for entry in entries {
+                        let entry = entry?;
+                        copy_recursive(
+                            &entry.path(),
+                            &dest.join(entry.file_name()),
+                        )?;
+                    }
+                    Ok(())
+                })
+            } else {
+                std::fs::copy(p, &dest).map(drop)
+            };
+            if let Err(e) = copied {
+                let _ = if is_dir {
+                    std::fs::remove_dir_all(&dest)
+                } else {
+                    std::fs::remove_file(&dest)
+                };
+                return Err(format!("{}: {}", dest.display(), e).into());
+            }
+            if is_dir {
+                std::fs::remove_dir_all(p)?;
+            } else {
+                std::fs::remove_file(p)?;
+            }
+        }
+        moved.push(dest);
+    }
+    Ok(moved)
+}
+
+/// Copies a file or a directory tree we created ourselves (so no
+/// symlinks to worry about).
+fn copy_recursive(src: &Path, dest: &Path) -> std::io::Result<()> {
+    if src.is_dir() {
+        std::fs::DirBuilder::new().mode(0o700).create(dest)?;
+        for entry in std::fs::read_dir(src)? {
+            let entry = entry?;
+            copy_recursive(&entry.path(), &dest.join(entry.file_name()))?;
+        }
+        Ok(())
+    } else {
+        std::fs::copy(src, dest).map(|_| ())
+    }
+}
+
+fn format_command(cmd: &std::process::Command) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    parts.push(cmd.get_program().to_string_lossy().into_owned());
+    for a in cmd.get_args() {
+        parts.push(a.to_string_lossy().into_owned());
+    }
+    parts
+        .iter()
+        .map(|s| match shlex::try_quote(s) {
+            Ok(q) => q.into_owned(),
+            Err(_) => format!("{:?}", s),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+async fn handle_socket_client(
+    stream: UnixStream,
+    outbound_tx: mpsc::Sender<Message>,
+    pending: Arc<Mutex<HashMap<u64, mpsc::UnboundedSender<Message>>>>,
+    peer_limits: Arc<Mutex<Option<OpenLimits>>>,
+    peer_alive: watch::Receiver<bool>,
+) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel::<Message>();
+    // A client may send several requests (`open --follow` sends updates),
+    // so the connection lasts until it hangs up.
+    let mut req_ids: Vec<u64> = Vec::new();
+    let mut opens: HashSet<u64> = HashSet::new();
+    let mut followed: Vec<u64> = Vec::new();
+    // `peer_wait`s run beside the reads, so a client that hangs up
+    // mid-wait is noticed; dropping the set cancels them.
+    let mut waits = tokio::task::JoinSet::new();
+
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                match line {
+                    Ok(Some(l)) => {
+                        match serde_json::from_str::<Message>(&l) {
+                            Ok(Message::Unknown) => {
+                                warn!("socket message of an unknown type (ignored)");
+                            }
+                            Ok(Message::PeerWait { timeout_ms }) => {
+                                let mut peer_alive = peer_alive.clone();
+                                let peer_limits = peer_limits.clone();
+                                let reply_tx = reply_tx.clone();
+                                waits.spawn(async move {
+                                    let connected = time::timeout(
+                                        Duration::from_millis(timeout_ms),
+                                        peer_alive.wait_for(|alive| *alive),
+                                    )
+                                    .await
+                                    .is_ok_and(|r| r.is_ok());
+                                    let limits = *peer_limits.lock().await;
+                                    let _ = reply_tx.send(Message::PeerStatus {
+                                        connected,
+                                        limits,
+                                    });
+                                });
+                            }
+                            Ok(msg) => {
+                                if let Message::OpenBegin { request_id, .. }
+                                | Message::ClipGet { request_id }
+                                | Message::Clip {
+                                    request_id: Some(request_id), ..
+                                } = &msg
+                                {
+                                    req_ids.push(*request_id);
+                                    pending
+                                        .lock()
+                                        .await
+                                        .insert(*request_id, reply_tx.clone());
+                                }
+                                if let Message::OpenBegin {
+                                    request_id, follow, ..
+                                } = &msg
+                                {
+                                    opens.insert(*request_id);
+                                    if *follow {
+                                        followed.push(*request_id);
+                                    }
+                                }
+                                // Waits while the queue is full; see
+                                // `OUTBOUND_QUEUE`.
+                                if outbound_tx.send(msg).await.is_err() {
+                                    warn!("outbound channel closed");
+                                    break;
+                                }
+                            }
+                            Err(e) => {
+                                error!("socket parse error: {}", e);
+                                break;
+                            }
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("socket read error: {}", e);
+                        break;
+                    }
+                }
+            }
+            reply = reply_rx.recv() => {
+                match reply {
+                    Some(msg) => {
+                        let s = match serde_json::to_string(&msg) {
+                            Ok(s) => s,
+                            Err(e) => {
+                                error!("serialize reply: {}", e);
+                                break;
+                            }
+                        };
+                        if writer.write_all(s.as_bytes()).await.is_err() {
+                            break;
+                        }
+                        if writer.write_all(b"\n").await.is_err() {
+                            break;
+                        }
+                        let _ = writer.flush().await;
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+    // Requests still awaiting their result were given up on; let the
+    // receiver clean up instead of waiting for chunks that never come.
+    let mut cancel = Vec::new();
+    {
+        let mut pending = pending.lock().await;
+        for id in req_ids {
+            if pending.remove(&id).is_some() && opens.contains(&id) {
+                cancel.push(id);
+            }
+        }
+    }
+    for request_id in cancel {
+        info!("request_id={} client went away; cancelling", request_id);
+        let _ = outbound_tx.send(Message::OpenCancel { request_id }).await;
+    }
+    for request_id in followed {
+        let _ = outbound_tx.send(Message::OpenUnfollow { request_id }).await;
+    }
+}
+
+/// Returns the exit status to leave with, mirroring the local command's.
+async fn run_open_client(
+    name: &str,
+    raw_args: Vec<OsString>,
+) -> Result<i32, Box<dyn std::error::Error>> {
+    // Ours, not the local command's; everything else passes through,
+    // UTF-8 or not.
+    let mut follow = false;
+    let mut stdin_name: Option<String> = None;
+    // `None` bundles HTML pages only.
+    let mut bundle: Option<bool> = None;
+    let mut wait_for_client: Option<Duration> = None;
+    let mut rest = Vec::with_capacity(raw_args.len());
+    let mut iter = raw_args.into_iter().peekable();
+    while let Some(arg) = iter.next() {
+        if arg == "--follow" {
+            follow = true;
+        } else if arg == "--wait-for-client" {
+            // The timeout is optional: take the next argument only if it
+            // reads as one.
+            let timeout = iter
+                .peek()
+                .and_then(|v| v.to_str())
+                .and_then(|v| gc::parse_duration(v).ok());
+            if timeout.is_some() {
+                iter.next();
+            }
+            wait_for_client = Some(timeout.unwrap_or(Duration::MAX));
+        } else if let Some(v) =
+            arg.to_str().and_then(|a| a.strip_prefix("--wait-for-client="))
+        {
+            let timeout = gc::parse_duration(v)
+                .map_err(|e| format!("--wait-for-client {:?}: {}", v, e))?;
+            wait_for_client = Some(timeout);
+        } else if arg == "--bundle" {
+            bundle = Some(true);
+        } else if arg == "--no-bundle" {
+            bundle = Some(false);
+        } else if arg == "--name" {
+            let v = iter.next().ok_or("--name needs a value")?;
+            stdin_name =
+                Some(v.into_string().map_err(|_| "--name must be UTF-8")?);
+        } else if let Some(v) =
+            arg.to_str().and_then(|a| a.strip_prefix("--name="))
+        {
+            stdin_name = Some(v.to_string());
+        } else {
+            rest.push(arg);
+        }
+    }
+    let args = rest;
+
+    if args.is_empty() {
+        return Err("open: no arguments".into());
+    }
+    if follow && bundle == Some(true) {
+        return Err("--follow cannot be used with --bundle".into());
+    }
+
+    // The receiver's limits decide what can be sent, so ask first.
+    let (stream, peer_limits) = connect_control_socket(wait_for_client).await?;
+    let mut conn = RequestConn::new(stream, peer_limits);
+    let mut plan = OpenPlan::new(conn.limits());
+    let mut used_stdin = false;
+
+    for arg in &args {
+        // `-` and macOS's `open -f` read the content from stdin.
+        if arg == "-" || arg == "-f" {
+            if used_stdin {
+                return Err("stdin can only be read once".into());
+            }
+            used_stdin = true;
+            let data = read_stdin_source(&plan.limits).await?;
+            let basename = stdin_basename(stdin_name.as_deref(), &data);
+            info!(
+                "open-client: {:?} -> sync stdin as {} ({} bytes)",
+                arg,
+                basename,
+                data.len()
+            );
+            let index = plan.add_bytes(basename, data)?;
+            plan.slots.push(ArgSlot::File { index });
+            continue;
+        }
+        let bytes = arg.as_bytes();
+        let is_flag = bytes.starts_with(b"-");
+        let is_url = bytes.windows(3).any(|w| w == b"://");
+        let literal_reason = if is_flag {
+            "flag".to_string()
+        } else if is_url {
+            "url".to_string()
+        } else {
+            match std::fs::metadata(arg) {
+                Ok(meta) if meta.is_file() => {
+                    let path = std::fs::canonicalize(arg)?;
+                    let bundled =
+                        bundle.unwrap_or_else(|| !follow && is_html(&path));
+                    if bundled {
+                        let before = (plan.files.len(), plan.total);
+                        let index = plan.add_bundle(path, meta.len())?;
+                        info!(
+                            "open-client: {:?} -> sync as bundle ({} files, \
+                             {} bytes)",
+                            arg,
+                            plan.files.len() - before.0,
+                            plan.total - before.1
+                        );
+                        plan.slots.push(ArgSlot::File { index });
+                        continue;
+                    }
+                    let remote_path = path.to_str().map(|s| s.to_string());
+                    let index = plan.add_file(path, meta.len(), None)?;
+                    plan.files[index as usize].remote_path = remote_path;
+                    info!(
+                        "open-client: {:?} -> sync as file ({} bytes)",
+                        arg,
+                        meta.len()
+                    );
+                    plan.slots.push(ArgSlot::File { index });
+                    continue;
+                }
+                Ok(meta) if meta.is_dir() => {
+                    let path = std::fs::canonicalize(arg)?;
+                    let before = (plan.files.len(), plan.total);
+                    let index = plan.add_tree(&path)?;
+                    info!(
+                        "open-client: {:?} -> sync as directory ({} files, {} \
+                         bytes)",
+                        arg,
+                        plan.files.len() - before.0,
+                        plan.total - before.1
+                    );
+                    plan.slots.push(ArgSlot::Tree { index });
+                    continue;
+                }
+                Ok(meta) => format!(
+                    "stat ok but not a regular file or directory (symlink={})",
+                    meta.file_type().is_symlink()
+                ),
+                Err(e) => format!("stat failed: {}", e),
+            }
+        };
+
+        info!(
+            "open-client: {:?} -> passed through literally ({})",
+            arg, literal_reason
+        );
+        let (value, value_raw) = wire_name(arg);
+        plan.slots.push(ArgSlot::Literal { value, value_raw });
+    }
+
+    if stdin_name.is_some() && !used_stdin {
+        return Err("--name only applies to stdin (`-`)".into());
+    }
+    if follow {
+        if !plan.trees.is_empty() {
+            return Err("--follow works on files, not directories".into());
+        }
+        if used_stdin {
+            return Err("--follow cannot follow stdin".into());
+        }
+        if plan.files.is_empty() {
+            return Err("--follow needs at least one existing file".into());
+        }
+    }
+
+    let mut request_id: u64 = rand::thread_rng().gen();
+    let opts = BeginOpts { follow, ..Default::default() };
+    let Message::OpenResult { ok, error, paths, run, .. } =
+        conn.send(&mut request_id, name, opts, &mut plan).await?
+    else {
+        return Err("unexpected reply".into());
+    };
+    for path in paths {
+        eprintln!("{}: saved without opening: {}", name, path);
+    }
+    // Mirror the local command: its output, then its exit status.
+    let mut code = 0;
+    if let Some(run) = run {
+        for path in &run.opened {
+            eprintln!("{}: opened {}", name, path);
+        }
+        print!("{}", run.stdout);
+        eprint!("{}", run.stderr);
+        code = match (run.exit_code, run.signal) {
+            (Some(c), _) => c,
+            (None, Some(sig)) => 128 + sig,
+            (None, None) => 1,
+        };
+    }
+    if !ok {
+        eprintln!("{}: {}", name, error.as_deref().unwrap_or("open failed"));
+        return Ok(if code == 0 { 1 } else { code });
+    }
+    if follow {
+        let paths: Vec<PathBuf> = plan
+            .sources
+            .iter()
+            .filter_map(|s| match s {
+                FileSource::Path(p) => Some(p.clone()),
+                FileSource::Bytes(_) => None,
+            })
+            .collect();
+        follow_files(&mut conn, name, request_id, &paths, plan.limits).await?;
+    }
+    Ok(code)
+}
+
+/// `open --follow`: polls the opened files and, once a change has settled
+/// for one interval, sends the new contents to replace the local copies.
+/// Runs until interrupted or the receiver forgets the request; closing
+/// the socket ends the follow on the receiver too.
+async fn follow_files(
+    conn: &mut RequestConn,
+    name: &str,
+    request_id: u64,
+    paths: &[PathBuf],
+    limits: OpenLimits,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let stat = || -> Option<Vec<(u64, SystemTime)>> {
+        paths
+            .iter()
+            .map(|p| {
+                let meta = std::fs::metadata(p).ok()?;
+                Some((meta.len(), meta.modified().ok()?))
+            })
+            .collect()
+    };
+    info!("{}: following {} file(s) until interrupted", name, paths.len());
+    let mut sent = stat();
+    let mut seen = sent.clone();
+    let mut poll = time::interval(FOLLOW_POLL_INTERVAL);
+    loop {
+        poll.tick().await;
+        let now = stat();
+        if let Some(stats) = now.as_ref().filter(|n| sent.as_ref() != Some(n)) {
+            if now == seen {
+                let mut update = OpenPlan::new(limits);
+                let planned = paths.iter().zip(stats).try_for_each(
+                    |(path, (size, _))| {
+                        update.add_file(path.clone(), *size, None).map(|_| ())
+                    },
+                );
+                let opts = BeginOpts {
+                    update_of: Some(request_id),
+                    ..Default::default()
+                };
+                let result = match planned {
+                    Ok(()) => {
+                        let mut id: u64 = rand::thread_rng().gen();
+                        open_result(
+                            conn.send(&mut id, name, opts, &mut update).await?,
+                        )
+                    }
+                    Err(e) => Err(e.to_string()),
+                };
+                match result {
+                    Ok(updated) => {
+                        for p in updated {
+                            info!("{}: updated {}", name, p);
+                        }
+                        sent = now.clone();
+                    }
+                    // The receiver forgot the request (its client
+                    // restarted); no later update can succeed.
+                    Err(e) if e.ends_with("is not being followed") => {
+                        warn!("{}: {}; no longer following", name, e);
+                        return Ok(());
+                    }
+                    // Retried once the file settles again.
+                    Err(e) => warn!("{}: update failed: {}", name, e),
+                }
+            }
+        }
+        seen = now;
+    }
+}
+
+/// A control-socket connection carrying open requests and their
+/// replies.
+struct RequestConn {
+    lines: tokio::io::Lines<BufReader<tokio::net::unix::OwnedReadHalf>>,
+    writer: tokio::net::unix::OwnedWriteHalf,
+    /// What the relay reported its peer advertised; see `peer_status`.
+    peer_limits: Option<OpenLimits>,
+}
+
+impl RequestConn {
+    fn new(stream: UnixStream, peer_limits: Option<OpenLimits>) -> Self {
+        let (reader, writer) = stream.into_split();
+        RequestConn {
+            lines: BufReader::new(reader).lines(),
+            writer,
+            peer_limits,
+        }
+    }
+
+    /// The receiver's limits; the defaults for one that doesn't advertise
+    /// them.
+    fn limits(&self) -> OpenLimits {
+        self.peer_limits.unwrap_or_default()
+    }
+
+    /// Sends `plan` as one request and waits for its `open_result`. The
+    /// error is the socket failing; the receiver's verdict is in the
+    /// result (see `open_result`). A request the relay asks to restart
+    /// is sent again under a new `request_id`.
+    async fn send(
+        &mut self,
+        request_id: &mut u64,
+        name: &str,
+        opts: BeginOpts,
+        plan: &mut OpenPlan,
+    ) -> Result<Message, Box<dyn std::error::Error>> {
+        plan.hash_files().await?;
+        loop {
+            match self.send_once(*request_id, name, &opts, plan).await? {
+                Message::OpenRestart { .. } => {
+                    *request_id = rand::thread_rng().gen();
+                    eprintln!(
+                        "{}: the link to the clipcast client dropped; sending \
+                         again",
+                        name
+                    );
+                }
+                reply => return Ok(reply),
+            }
+        }
+    }
+
+    async fn send_once(
+        &mut self,
+        request_id: u64,
+        name: &str,
+        opts: &BeginOpts,
+        plan: &OpenPlan,
+    ) -> Result<Message, Box<dyn std::error::Error>> {
+        let begin = Message::OpenBegin {
+            request_id,
+            name: name.to_string(),
+            files: plan.files.clone(),
+            trees: plan.trees.clone(),
+            extra_args: plan.slots.clone(),
+            dest: opts.dest.clone(),
+            follow: opts.follow,
+            update_of: opts.update_of,
+            edit: opts.edit,
+        };
+        write_json_line(&mut self.writer, &begin).await?;
+
+        // The receiver answers with the files it already has; anything
+        // else arriving first (a failed begin) is the final result. One
+        // that doesn't advertise its limits predates `open_have` and would
+        // leave us waiting.
+        let mut have: HashSet<u32> = HashSet::new();
+        if !plan.files.is_empty() && self.peer_limits.is_some() {
+            match self.recv_reply(request_id).await? {
+                Message::OpenHave { indices, .. } => have.extend(indices),
+                reply => return Ok(reply),
+            }
+            if !have.is_empty() {
+                info!(
+                    "{}: {} of {} files already on the receiver",
+                    name,
+                    have.len(),
+                    plan.files.len()
+                );
+            }
+        }
+
+        // Progress and an early result can arrive while the chunks are
+        // still going out, so read replies alongside the writes.
+        let total = plan
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !have.contains(&(*i as u32)))
+            .map(|(_, f)| f.size)
+            .sum();
+        let mut progress = ProgressBar::new(name, total);
+        let stop = Cell::new(false);
+        let stream =
+            stream_sources(&mut self.writer, request_id, plan, &have, &stop);
+        tokio::pin!(stream);
+        let mut streaming = true;
+        let lines = &mut self.lines;
+        loop {
+            tokio::select! {
+                res = &mut stream, if streaming => {
+                    res?;
+                    streaming = false;
+                    if stop.get() {
+                        progress.finish();
+                        return Ok(Message::OpenRestart { request_id });
+                    }
+                }
+                reply = next_reply(lines, request_id), if !stop.get() => {
+                    match reply? {
+                        Message::OpenProgress { bytes, .. } => {
+                            progress.update(bytes)
+                        }
+                        // Whole lines only: let the chunk being written
+                        // finish, then stop.
+                        Message::OpenRestart { .. } if streaming => {
+                            stop.set(true)
+                        }
+                        reply => {
+                            progress.finish();
+                            return Ok(reply);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Next `open_have` or `open_result` for `request_id`.
+    async fn recv_reply(
+        &mut self,
+        request_id: u64,
+    ) -> Result<Message, Box<dyn std::error::Error>> {
+        loop {
+            match next_reply(&mut self.lines, request_id).await? {
+                Message::OpenProgress { .. } => continue,
+                msg => return Ok(msg),
+            }
+        }
+    }
+}
+
+/// Writes the chunks of every source the receiver doesn't already have,
+/// until `stop` is set.
+async fn stream_sources(
+    writer: &mut tokio::net::unix::OwnedWriteHalf,
+    request_id: u64,
+    plan: &OpenPlan,
+    have: &HashSet<u32>,
+    stop: &Cell<bool>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (idx, source) in plan.sources.iter().enumerate() {
+        if have.contains(&(idx as u32)) {
+            continue;
+        }
+        let mut f: Box<dyn tokio::io::AsyncRead + Unpin + Send> = match source {
+            FileSource::Path(path) => Box::new(tfs::File::open(path).await?),
+            FileSource::Bytes(data) => Box::new(&data[..]),
+        };
+        let mut buf = vec![0u8; OPEN_CHUNK_SIZE];
+        loop {
+            if stop.get() {
+                return Ok(());
+            }
+            let n = f.read(&mut buf).await?;
+            if n == 0 {
+                let msg = Message::OpenChunk {
+                    request_id,
+                    index: idx as u32,
+                    data_b64: String::new(),
+                    eof: true,
+                };
+                write_json_line(writer, &msg).await?;
+                break;
+            }
+            let data_b64 = B64.encode(&buf[..n]);
+            let msg = Message::OpenChunk {
+                request_id,
+                index: idx as u32,
+                data_b64,
+                eof: false,
+            };
+            write_json_line(writer, &msg).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Next reply (`open_have`, `open_progress`, `edit_write`, `open_restart`
+/// or `open_result`) for `request_id`.
+/// Cancel-safe, so it can race the chunk writes.
+async fn next_reply(
+    lines: &mut tokio::io::Lines<BufReader<tokio::net::unix::OwnedReadHalf>>,
+    request_id: u64,
+) -> Result<Message, Box<dyn std::error::Error>> {
+    while let Some(line) = lines.next_line().await? {
+        let msg = serde_json::from_str::<Message>(&line)?;
+        match &msg {
+            Message::OpenHave { request_id: rid, .. }
+            | Message::OpenProgress { request_id: rid, .. }
+            | Message::EditWrite { request_id: rid, .. }
+            | Message::OpenRestart { request_id: rid }
+            | Message::OpenResult { request_id: rid, .. }
+                if *rid == request_id =>
+            {
+                return Ok(msg)
+            }
+            _ => continue,
+        }
+    }
+    Err("socket closed before result".into())
+}
+
+/// A one-line transfer progress bar on stderr, drawn only when stderr is a
+/// terminal.
+struct ProgressBar {
+    name: String,
+    total: u64,
+    start: time::Instant,
+    enabled: bool,
+    drawn: bool,
+}
+
+impl ProgressBar {
+    fn new(name: &str, total: u64) -> Self {
+        ProgressBar {
+            name: name.to_string(),
+            total,
+            start: time::Instant::now(),
+            enabled: std::io::stderr().is_terminal(),
+            drawn: false,
+        }
+    }
+
+    fn update(&mut self, bytes: u64) {
+        if !self.enabled || self.total == 0 {
+            return;
+        }
+        let bytes = bytes.min(self.total);
+        let secs = self.start.elapsed().as_secs_f64();
+        let rate = if secs > 0.0 { bytes as f64 / secs } else { 0.0 };
+        let eta = if rate > 0.0 {
+            let left = ((self.total - bytes) as f64 / rate) as u64;
+            format!("{}:{:02}", left / 60, left % 60)
+        } else {
+            "-:--".to_string()
+        };
+        const WIDTH: u64 = 24;
+        let filled = (bytes * WIDTH / self.total) as usize;
+        eprint!(
+            "\r\x1b[K{}: [{}{}] {} / {} {}/s ETA {}",
+            self.name,
+            "#".repeat(filled),
+            " ".repeat(WIDTH as usize - filled),
+            gc::format_size(bytes),
+            gc::format_size(self.total),
+            gc::format_size(rate as u64),
+            eta
+        );
+        self.drawn = true;
+    }
+
+    /// Clears the bar so the result prints on a clean line.
+    fn finish(&mut self) {
+        if self.drawn {
+            eprint!("\r\x1b[K");
+            self.drawn = false;
+        }
+    }
+}
+
+/// The receiver's verdict from an `open_result`.
+fn open_result(reply: Message) -> Result<Vec<String>, String> {
+    match reply {
+        Message::OpenResult { ok: true, paths, .. } => Ok(paths),
+        Message::OpenResult { error, .. } => {
+            Err(error.unwrap_or_else(|| "open failed".into()))
+        }
+        _ => Err("unexpected reply".into()),
+    }
+}
+
+/// `clipcast push`: sends local files and directories to the remote
+/// through the running client for `--host`, and prints where they landed.
+async fn run_push(cmd: PushCmd) -> Result<(), Box<dyn std::error::Error>> {
+    let sock = client_control_socket(&cmd.host);
+    let mut stream = UnixStream::connect(&sock).await.map_err(|e| {
+        format!(
+            "connect {}: {} (is `clipcast client --host {}` running?)",
+            sock.display(),
+            e,
+            cmd.host
+        )
+    })?;
+    let Some((connected, peer_limits)) =
+        peer_status(&mut stream, Duration::ZERO).await?
+    else {
+        return Err(format!(
+            "the client for {} hung up without answering; it is older than \
+             this clipcast. Restart it",
+            cmd.host
+        )
+        .into());
+    };
+    if !connected {
+        return Err(format!(
+            "the client for {} is not connected to its server",
+            cmd.host
+        )
+        .into());
+    }
+    let mut conn = RequestConn::new(stream, peer_limits);
+    let mut plan = OpenPlan::new(conn.limits());
+    for arg in &cmd.paths {
+        let meta = std::fs::metadata(arg)
+            .map_err(|e| format!("{}: {}", arg.display(), e))?;
+        let path = std::fs::canonicalize(arg)?;
+        if meta.is_file() {
+            let index = plan.add_file(path, meta.len(), None)?;
+            plan.slots.push(ArgSlot::File { index });
+        } else if meta.is_dir() {
+            let index = plan.add_tree(&path)?;
+            plan.slots.push(ArgSlot::Tree { index });
+        } else {
+            return Err(format!(
+                "{}: not a regular file or directory",
+                arg.display()
+            )
+            .into());
+        }
+    }
+
+    let mut request_id: u64 = rand::thread_rng().gen();
+    let opts = BeginOpts { dest: cmd.to, ..Default::default() };
+    for path in
+        open_result(conn.send(&mut request_id, "push", opts, &mut plan).await?)?
+    {
+        println!("{}", path);
+    }
+    Ok(())
+}
+
+/// Where the bytes of a planned file come from.
+enum FileSource {
+    Path(PathBuf),
+    /// Content read up front (stdin).
+    Bytes(Vec<u8>),
+}
+
+/// Reads all of stdin for a `-` argument, up to the per-file limit.
+async fn read_stdin_source(
+    limits: &OpenLimits,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut data = Vec::new();
+    tokio::io::stdin()
+        .take(limits.max_file_size + 1)
+        .read_to_end(&mut data)
+        .await?;
+    if data.len() as u64 > limits.max_file_size {
+        return Err(format!(
+            "stdin: exceeds the receiver's per-file limit of {}",
+            gc::format_size(limits.max_file_size)
+        )
+        .into());
+    }
+    Ok(data)
+}
+
+/// File name for stdin content: `--name` if given, else `stdin`, with an
+/// extension guessed from the content when the name has none.
+fn stdin_basename(name: Option<&str>, data: &[u8]) -> String {
+    let name = name.unwrap_or("stdin");
+    if Path::new(name).extension().is_some() {
+        return name.to_string();
+    }
+    let ext = match policy::sniff_extension(data) {
+        Some(ext) => ext,
+        None if std::str::from_utf8(data).is_ok() => "txt",
+        None => "bin",
+    };
+    format!("{}.{}", name, ext)
+}
+
+/// Everything the remote `open` CLI will send: files to stream (with
+/// where to read them), directory trees, and the argument slots that tie
+/// them back to the command line.
+#[derive(Default)]
+struct OpenPlan {
+    /// The receiver's, as advertised.
+    limits: OpenLimits,
+    sources: Vec<FileSource>,
+    files: Vec<OpenFileMeta>,
+    trees: Vec<OpenTreeMeta>,
+    slots: Vec<ArgSlot>,
+    total: u64,
+}
+
+impl OpenPlan {
+    fn new(limits: OpenLimits) -> Self {
+        OpenPlan { limits, ..Default::default() }
+    }
+
+    /// Queues one file for streaming, enforcing the per-request limits.
+    /// `tree` is the owning tree index and the path below its root.
+    fn add_file(
+        &mut self,
+        path: PathBuf,
+        size: u64,
+        tree: Option<(u32, String)>,
+    ) -> Result<u32, Box<dyn std::error::Error>> {
+        self.check_limits(&path.display().to_string(), size)?;
+        let (basename, basename_raw) =
+            wire_name(path.file_name().ok_or("bad file basename")?);
+        let index = self.files.len() as u32;
+        let (tree, rel_path) = match tree {
+            Some((t, rel)) => (Some(t), rel),
+            None => (None, String::new()),
+        };
+        let stat = std::fs::metadata(&path).ok();
+        self.files.push(OpenFileMeta {
+            basename,
+            basename_raw,
+            size,
+            tree,
+            rel_path,
+            sha256: None,
+            remote_path: None,
+            mtime: stat.as_ref().map(|m| m.mtime()),
+            mode: stat.as_ref().map(|m| m.mode() & 0o7777),
+        });
+        self.sources.push(FileSource::Path(path));
+        Ok(index)
+    }
+
+    /// Fills in the hash of every file read from disk, on the blocking
+    /// pool so large files don't stall the runtime.
+    async fn hash_files(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let pending: Vec<(usize, PathBuf)> = self
+            .sources
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.files[*i].sha256.is_none())
+            .filter_map(|(i, source)| match source {
+                FileSource::Path(path) => Some((i, path.clone())),
+                FileSource::Bytes(_) => None,
+            })
+            .collect();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let digests = tokio::task::spawn_blocking(move || {
+            pending
+                .into_iter()
+                .map(|(i, path)| {
+                    let mut file = std::fs::File::open(&path)?;
+                    let mut hasher = Sha256::new();
+                    std::io::copy(&mut file, &mut hasher)?;
+                    Ok((i, hex_digest(hasher)))
+                })
+                .collect::<std::io::Result<Vec<_>>>()
+        })
+        .await??;
+        for (i, digest) in digests {
+            self.files[i].sha256 = Some(digest);
+        }
+        Ok(())
+    }
+
+    /// Queues in-memory content as a top-level file named `basename`.
+    fn add_bytes(
+        &mut self,
+        basename: String,
+        data: Vec<u8>,
+    ) -> Result<u32, Box<dyn std::error::Error>> {
+        let size = data.len() as u64;
+        self.check_limits(&basename, size)?;
+        let index = self.files.len() as u32;
+        self.files.push(OpenFileMeta {
+            basename,
+            size,
+            sha256: Some(hex_digest(Sha256::new_with_prefix(&data))),
+            ..Default::default()
+        });
+        self.sources.push(FileSource::Bytes(data));
+        Ok(index)
+    }
+
+    /// Accounts for one more file of `size` bytes, failing if it breaks
+    /// a per-file or per-request limit.
+    fn check_limits(
+        &mut self,
+        what: &str,
+        size: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let limits = self.limits;
+        if size > limits.max_file_size {
+            return Err(format!(
+                "{}: {} exceeds the receiver's per-file limit of {}",
+                what,
+                gc::format_size(size),
+                gc::format_size(limits.max_file_size)
+            )
+            .into());
+        }
+        self.total = self.total.saturating_add(size);
+        if self.total > limits.max_total_size {
+            return Err(format!(
+                "total size exceeds the receiver's limit of {}",
+                gc::format_size(limits.max_total_size)
+            )
+            .into());
+        }
+        if self.files.len() >= limits.max_files as usize {
+            return Err(
+                format!("too many files (max {})", limits.max_files).into()
+            );
+        }
+        Ok(())
+    }
+
+    /// Queues a page with the files it references (see `bundle`), as a
+    /// tree rooted where they all live so relative links still work on
+    /// the receiver. Returns the page's index, to be opened in place of
+    /// the tree. References that would break a limit or the bundle cap
+    /// are left out; a page that references nothing goes as a plain file.
+    fn add_bundle(
+        &mut self,
+        page: PathBuf,
+        size: u64,
+    ) -> Result<u32, Box<dyn std::error::Error>> {
+        let (root, assets) = bundle::collect(&page);
+        let Some(root_name) = root.file_name().filter(|_| !assets.is_empty())
+        else {
+            return self.add_file(page, size, None);
+        };
+        let (basename, basename_raw) = wire_name(root_name);
+        let tree = self.trees.len() as u32;
+        let rel_path = |p: &Path| {
+            p.strip_prefix(&root)
+                .unwrap_or(p)
+                .iter()
+                .map(|c| local_name(c.as_bytes()))
+                .collect::<Vec<_>>()
+                .join("/")
+        };
+        let index =
+            self.add_file(page.clone(), size, Some((tree, rel_path(&page))))?;
+        let mut budget = bundle::MAX_ASSETS_SIZE
+            .min(self.limits.max_total_size.saturating_sub(self.total));
+        let mut left_out = 0;
+        for asset in assets {
+            let Ok(meta) = std::fs::metadata(&asset) else { continue };
+            if meta.len() > budget
+                || meta.len() > self.limits.max_file_size
+                || self.files.len() >= self.limits.max_files as usize
+            {
+                left_out += 1;
+                continue;
+            }
+            budget -= meta.len();
+            let rel = rel_path(&asset);
+            self.add_file(asset, meta.len(), Some((tree, rel)))?;
+        }
+        if left_out > 0 {
+            warn!(
+                "open-client: {}: {} referenced file(s) left out of the \
+                 bundle (too large)",
+                page.display(),
+                left_out
+            );
+        }
+        // The receiver creates the files' directories as it goes.
+        self.trees.push(OpenTreeMeta {
+            basename,
+            basename_raw,
+            dirs: Vec::new(),
+        });
+        Ok(index)
+    }
+
+    /// Walks a directory argument and queues every regular file in it.
+    /// Symlinks and special files inside the tree are skipped rather than
+    /// followed, so the transfer can't wander outside `root`.
+    fn add_tree(
+        &mut self,
+        root: &Path,
+    ) -> Result<u32, Box<dyn std::error::Error>> {
+        let (basename, basename_raw) =
+            wire_name(root.file_name().ok_or("bad directory basename")?);
+        let index = self.trees.len() as u32;
+        let mut dirs: Vec<String> = Vec::new();
+        let mut stack: Vec<(PathBuf, String)> =
+            vec![(root.to_path_buf(), String::new())];
+        while let Some((dir, rel)) = stack.pop() {
+            let mut entries: Vec<_> =
+                std::fs::read_dir(&dir)?.collect::<Result<_, _>>()?;
+            entries.sort_by_key(|e| e.file_name());
+            // Reversed so the stack pops subdirectories in name order.
+            for entry in entries.into_iter().rev() {
+                let name = local_name(entry.file_name().as_bytes());
+                if entry.file_name().to_str().is_none() {
+                    info!(
+                        "open-client: sending non-UTF-8 {:?} as {}",
+                        entry.path(),
+                        name
+                    );
                 }
-            }
-            reply = reply_rx.recv() => {
-                match reply {
-                    Some(msg) => {
-                        let done = matches!(msg, Message::OpenResult { .. });
-                        let s = match serde_json::to_string(&msg) {
-                            Ok(s) => s,
-                            Err(e) => {
-                                error!("serialize reply: {}", e);
-                                break;
-                            }
-                        };
-                        if writer.write_all(s.as_bytes()).await.is_err() {
-                            break;
-                        }
-                        if writer.write_all(b"\n").await.is_err() {
-                            break;
-                        }
-                        let _ = writer.flush().await;
-                        if done {
-                            break;
-                        }
+                let child_rel = if rel.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}/{}", rel, name)
+                };
+                let meta = entry.path().symlink_metadata()?;
+                if meta.is_dir() {
+                    if dirs.len() >= self.limits.max_files as usize {
+                        return Err(format!(
+                            "{}: too many directories (max {})",
+                            root.display(),
+                            self.limits.max_files
+                        )
+                        .into());
                     }
-                    None => break,
+                    dirs.push(child_rel.clone());
+                    stack.push((entry.path(), child_rel));
+                } else if meta.is_file() {
+                    self.add_file(
+                        entry.path(),
+                        meta.len(),
+                        Some((index, child_rel)),
+                    )?;
+                } else {
+                    info!(
+                        "open-client: skipping {} (not a regular file)",
+                        entry.path().display()
+                    );
                 }
             }
         }
-    }
-    if let Some(id) = req_id {
-        pending.lock().await.remove(&id);
+        // Parents before children, so the receiver can create them in
+        // order.
+        dirs.sort();
+        self.trees.push(OpenTreeMeta { basename, basename_raw, dirs });
+        Ok(index)
     }
 }
 
-async fn run_open_client(
-    raw_args: Vec<OsString>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = raw_args
-        .into_iter()
-        .map(|a| {
-            a.into_string()
-                .map_err(|bad| format!("non-UTF-8 argument: {:?}", bad))
-        })
-        .collect::<Result<_, _>>()?;
-
-    if args.is_empty() {
-        return Err("open: no arguments".into());
+/// `clipcast edit`: sends the file to the editor on the Mac and writes
+/// each save back over it until the editor closes. Returns the editor's
+/// exit status.
+async fn run_edit(cmd: EditCmd) -> Result<i32, Box<dyn std::error::Error>> {
+    let meta = std::fs::metadata(&cmd.path)
+        .map_err(|e| format!("{}: {}", cmd.path.display(), e))?;
+    if !meta.is_file() {
+        return Err(
+            format!("{}: not a regular file", cmd.path.display()).into()
+        );
     }
+    let path = std::fs::canonicalize(&cmd.path)?;
+    let (stream, peer_limits) = connect_control_socket(None).await?;
+    let mut conn = RequestConn::new(stream, peer_limits);
+    let mut plan = OpenPlan::new(conn.limits());
+    let index = plan.add_file(path.clone(), meta.len(), None)?;
+    plan.slots.push(ArgSlot::File { index });
+    plan.hash_files().await?;
+    // The contents the Mac's copy started from; see `write_back`.
+    let mut expected = plan.files[0].sha256.clone();
 
-    let mut file_paths: Vec<PathBuf> = Vec::new();
-    let mut file_metas: Vec<OpenFileMeta> = Vec::new();
-    let mut slots: Vec<ArgSlot> = Vec::with_capacity(args.len());
-    let mut total: u64 = 0;
-
-    for arg in &args {
-        let is_flag = arg.starts_with('-');
-        let is_url = arg.contains("://");
-        let mut is_file = false;
-        let mut size: u64 = 0;
-        let mut canon: Option<PathBuf> = None;
-        let mut literal_reason = String::new();
-
-        if is_flag {
-            literal_reason = "flag".into();
-        } else if is_url {
-            literal_reason = "url".into();
-        } else {
-            match std::fs::metadata(arg) {
-                Ok(meta) if meta.is_file() => {
-                    is_file = true;
-                    size = meta.len();
-                    canon = std::fs::canonicalize(arg).ok();
+    let mut request_id: u64 = rand::thread_rng().gen();
+    let opts = BeginOpts { edit: true, ..Default::default() };
+    eprintln!("edit: waiting for the editor on the Mac to close the file");
+    let mut reply = conn.send(&mut request_id, "edit", opts, &mut plan).await?;
+    loop {
+        match reply {
+            Message::EditWrite { data_b64, .. } => {
+                let data = B64.decode(&data_b64)?;
+                match write_back(&path, &data, &mut expected) {
+                    Ok(()) => eprintln!(
+                        "edit: saved {} ({} bytes)",
+                        path.display(),
+                        data.len()
+                    ),
+                    Err(e) => eprintln!("edit: {}", e),
                 }
-                Ok(meta) => {
-                    literal_reason = format!(
-                        "stat ok but not a regular file (dir={}, symlink={})",
-                        meta.is_dir(),
-                        meta.file_type().is_symlink()
-                    );
+            }
+            Message::OpenResult { ok, error, run, .. } => {
+                if let Some(run) = run {
+                    eprint!("{}", run.stderr);
+                    if !ok {
+                        eprintln!("edit: {}", error.unwrap_or_default());
+                    }
+                    return Ok(match (run.exit_code, run.signal) {
+                        (Some(c), _) => c,
+                        (None, Some(sig)) => 128 + sig,
+                        (None, None) => 1,
+                    });
                 }
-                Err(e) => {
-                    literal_reason = format!("stat failed: {}", e);
+                if ok {
+                    return Ok(0);
                 }
+                return Err(error
+                    .unwrap_or_else(|| "edit failed".into())
+                    .into());
+            }
+            // The editor's link back is gone with the session.
+            Message::OpenRestart { .. } => {
+                return Err("clipcast client disconnected while the editor \
+                            was open; saves so far are kept"
+                    .into());
             }
+            _ => return Err("unexpected reply".into()),
         }
+        reply = conn.recv_reply(request_id).await?;
+    }
+}
 
-        if is_file {
-            if size > MAX_OPEN_FILE_SIZE {
-                return Err(format!(
-                    "{}: exceeds per-file limit of {} bytes",
-                    arg, MAX_OPEN_FILE_SIZE
-                )
-                .into());
+/// Replaces `path` with an edited copy, atomically and keeping its
+/// permissions. If the file changed here since the Mac's copy was taken
+/// (`expected`), the edit goes beside it as `<name>.clipcast-conflict`
+/// instead and the remote change is left alone.
+fn write_back(
+    path: &Path,
+    data: &[u8],
+    expected: &mut Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let current = std::fs::read(path)
+        .ok()
+        .map(|d| hex_digest(Sha256::new_with_prefix(&d)));
+    let name = path.file_name().ok_or("bad file name")?;
+    let dir = path.parent().ok_or("file has no parent directory")?;
+    let sibling = |prefix: &str, suffix: &str| {
+        let mut s = OsString::from(prefix);
+        s.push(name);
+        s.push(suffix);
+        dir.join(s)
+    };
+    let target = if current == *expected {
+        path.to_path_buf()
+    } else {
+        sibling("", ".clipcast-conflict")
+    };
+    let rand_suffix =
+        Alphanumeric.sample_string(&mut rand::thread_rng(), 6).to_lowercase();
+    let tmp = sibling(".", &format!(".clipcast-{}", rand_suffix));
+    std::fs::write(&tmp, data)?;
+    if let Ok(meta) = std::fs::metadata(path) {
+        let _ = std::fs::set_permissions(&tmp, meta.permissions());
+    }
+    if let Err(e) = std::fs::rename(&tmp, &target) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    if target != path {
+        return Err(format!(
+            "{} changed since it was opened; the edit was saved to {}",
+            path.display(),
+            target.display()
+        )
+        .into());
+    }
+    *expected = Some(hex_digest(Sha256::new_with_prefix(data)));
+    Ok(())
+}
+
+/// Which way a clipboard shim moves data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClipShimMode {
+    Copy,
+    Paste,
+}
+
+/// Maps an `argv[0]` basename (plus its arguments) to a clipboard shim
+/// mode, or `None` if the name is not a clipboard tool we impersonate.
+/// Only the direction flags are interpreted; selection flags like
+/// `-selection clipboard` or `-b` are accepted and ignored.
+fn clip_shim_mode(name: &str, args: &[OsString]) -> Option<ClipShimMode> {
+    let has = |flags: &[&str]| {
+        args.iter().any(|a| a.to_str().is_some_and(|a| flags.contains(&a)))
+    };
+    match name {
+        "pbcopy" | "wl-copy" => Some(ClipShimMode::Copy),
+        "pbpaste" | "wl-paste" => Some(ClipShimMode::Paste),
+        "xclip" => {
+            if has(&["-o", "-out", "-output"]) {
+                Some(ClipShimMode::Paste)
+            } else {
+                Some(ClipShimMode::Copy)
             }
-            total = total.saturating_add(size);
-            if total > MAX_OPEN_TOTAL {
-                return Err(format!(
-                    "total size exceeds {} bytes",
-                    MAX_OPEN_TOTAL
-                )
-                .into());
+        }
+        "xsel" => {
+            if has(&["-o", "--output"]) {
+                Some(ClipShimMode::Paste)
+            } else if has(&["-i", "--input"]) {
+                Some(ClipShimMode::Copy)
+            } else if std::io::stdin().is_terminal() {
+                // Same default as xsel itself: no data piped in means the
+                // caller wants to read the selection.
+                Some(ClipShimMode::Paste)
+            } else {
+                Some(ClipShimMode::Copy)
             }
-            if file_metas.len() >= MAX_OPEN_FILES {
-                return Err(
-                    format!("too many files (max {})", MAX_OPEN_FILES).into()
-                );
+        }
+        _ => None,
+    }
+}
+
+/// `pbcopy`/`pbpaste`-style shim: sets or reads the local Mac clipboard
+/// through the running `clipcast server`, bypassing X and the poll loop.
+async fn run_clip_shim(
+    mode: ClipShimMode,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if std::env::var_os("CLIPCAST_NO_SHIM").is_some() {
+        // We were spawned by a clipcast server as its own clipboard
+        // command; relaying back into that server would loop.
+        return Err("invoked from a clipcast server clipboard command; \
+                    install the real tool or change \
+                    --read-clipboard-cmd/--write-clipboard-cmd"
+            .into());
+    }
+    let (stream, _) = connect_control_socket(None).await?;
+    let (reader, mut writer) = stream.into_split();
+    match mode {
+        ClipShimMode::Copy => {
+            let mut buf = Vec::new();
+            tokio::io::stdin().read_to_end(&mut buf).await?;
+            let clip = String::from_utf8_lossy(&buf).into_owned();
+            let request_id: u64 = rand::thread_rng().gen();
+            let msg = Message::Clip { clip, request_id: Some(request_id) };
+            write_json_line(&mut writer, &msg).await?;
+            let mut lines = BufReader::new(reader).lines();
+            let acked = async {
+                while let Some(line) = lines.next_line().await? {
+                    match serde_json::from_str::<Message>(&line)? {
+                        Message::Ack => return Ok(()),
+                        Message::OpenResult {
+                            request_id: rid, error, ..
+                        } if rid == request_id => {
+                            return Err(error.unwrap_or_default().into());
+                        }
+                        _ => continue,
+                    }
+                }
+                Err("socket closed before the clipboard was set".into())
+            };
+            match time::timeout(CLIP_ACK_TIMEOUT, acked).await {
+                Ok(result) => result,
+                Err(_) => Err("the clipcast client did not confirm the \
+                               clipboard"
+                    .into()),
             }
-            let path = canon.unwrap();
-            let basename = path
-                .file_name()
-                .and_then(|s| s.to_str())
-                .ok_or("bad file basename")?
-                .to_string();
-            let index = file_metas.len() as u32;
-            info!(
-                "open-client: {:?} -> sync as file ({} bytes)",
-                arg, size
-            );
-            file_metas.push(OpenFileMeta { basename, size });
-            file_paths.push(path);
-            slots.push(ArgSlot::File { index });
-        } else {
-            info!(
-                "open-client: {:?} -> passed through literally ({})",
-                arg, literal_reason
-            );
-            slots.push(ArgSlot::Literal { value: arg.clone() });
+        }
+        ClipShimMode::Paste => {
+            let request_id: u64 = rand::thread_rng().gen();
+            write_json_line(&mut writer, &Message::ClipGet { request_id })
+                .await?;
+            let mut lines = BufReader::new(reader).lines();
+            while let Some(line) = lines.next_line().await? {
+                match serde_json::from_str::<Message>(&line)? {
+                    Message::ClipValue { request_id: rid, clip }
+                        if rid == request_id =>
+                    {
+                        let mut out = tokio::io::stdout();
+                        out.write_all(clip.as_bytes()).await?;
+                        out.flush().await?;
+                        return Ok(());
+                    }
+                    // The daemon lost the client before it answered.
+                    Message::OpenResult { request_id: rid, error, .. }
+                        if rid == request_id =>
+                    {
+                        return Err(error.unwrap_or_default().into());
+                    }
+                    _ => continue,
+                }
+            }
+            Err("socket closed before clipboard arrived".into())
         }
     }
+}
 
+/// Connects to the server's control socket, failing unless a clipcast
+/// client is connected to the server. With `wait`, keeps trying for that
+/// long (`Duration::MAX` = until interrupted) instead: the server may be
+/// gone until ssh reconnects and starts a new one. Returns the limits the
+/// client advertised along with the connection.
+async fn connect_control_socket(
+    wait: Option<Duration>,
+) -> Result<(UnixStream, Option<OpenLimits>), Box<dyn std::error::Error>> {
     let sock = std::env::var("CLIPCAST_SOCK")
         .map(PathBuf::from)
         .unwrap_or_else(|_| default_control_socket());
-    let stream = UnixStream::connect(&sock)
-        .await
-        .map_err(|e| format!("connect {}: {}", sock.display(), e))?;
-    let (reader, mut writer) = stream.into_split();
-    let mut lines = BufReader::new(reader).lines();
-
-    let request_id: u64 = rand::thread_rng().gen();
-
-    let begin = Message::OpenBegin {
-        request_id,
-        files: file_metas.clone(),
-        extra_args: slots,
-    };
-    write_json_line(&mut writer, &begin).await?;
-
-    for (idx, path) in file_paths.iter().enumerate() {
-        let mut f = tfs::File::open(path).await?;
-        let mut buf = vec![0u8; OPEN_CHUNK_SIZE];
-        loop {
-            let n = f.read(&mut buf).await?;
-            if n == 0 {
-                let msg = Message::OpenChunk {
-                    request_id,
-                    index: idx as u32,
-                    data_b64: String::new(),
-                    eof: true,
-                };
-                write_json_line(&mut writer, &msg).await?;
-                break;
+    let deadline = wait.and_then(|w| time::Instant::now().checked_add(w));
+    let mut waiting = false;
+    loop {
+        let left = match (wait, deadline) {
+            (Some(_), Some(d)) => {
+                d.saturating_duration_since(time::Instant::now())
             }
-            let data_b64 = B64.encode(&buf[..n]);
-            let msg = Message::OpenChunk {
-                request_id,
-                index: idx as u32,
-                data_b64,
-                eof: false,
-            };
-            write_json_line(&mut writer, &msg).await?;
+            (Some(_), None) => Duration::MAX,
+            (None, _) => Duration::ZERO,
+        };
+        let failure = match UnixStream::connect(&sock).await {
+            Ok(mut stream) => match peer_status(&mut stream, left).await {
+                Ok(Some((true, limits))) => return Ok((stream, limits)),
+                Ok(Some((false, _))) => {
+                    "no clipcast client connected".to_string()
+                }
+                // Waiting won't help: it is the same server until the
+                // client reconnects with a fresh one.
+                Ok(None) => {
+                    return Err("the clipcast server here hung up without \
+                                answering; it is older than this clipcast. \
+                                Restart it (reconnect the clipcast client, \
+                                or stop `clipcast server --daemon`)"
+                        .into())
+                }
+                Err(e) => format!("no clipcast client connected ({})", e),
+            },
+            Err(e) => format!(
+                "no clipcast client connected (connect {}: {})",
+                sock.display(),
+                e
+            ),
+        };
+        if wait.is_none() || left.is_zero() {
+            return Err(failure.into());
         }
+        if !waiting {
+            info!("{}; waiting for one", failure);
+            waiting = true;
+        }
+        time::sleep(PEER_WAIT_RETRY).await;
     }
+}
 
+/// Asks the relay on `stream` whether its peer is connected, giving it up
+/// to `wait` to (re)connect, and what limits the peer advertised. `None`
+/// if it closed the socket instead, as relays that predate `peer_wait`
+/// do.
+async fn peer_status(
+    stream: &mut UnixStream,
+    wait: Duration,
+) -> Result<Option<(bool, Option<OpenLimits>)>, Box<dyn std::error::Error>> {
+    let timeout_ms = u64::try_from(wait.as_millis()).unwrap_or(u64::MAX);
+    write_json_line(stream, &Message::PeerWait { timeout_ms }).await?;
+    // The relay sends nothing else before the answer, so this doesn't
+    // read past it.
+    let mut lines = BufReader::new(stream).lines();
     while let Some(line) = lines.next_line().await? {
-        match serde_json::from_str::<Message>(&line)? {
-            Message::OpenResult { request_id: rid, ok, error }
-                if rid == request_id =>
-            {
-                if ok {
-                    return Ok(());
-                } else {
-                    return Err(error
-                        .unwrap_or_else(|| "open failed".into())
-                        .into());
-                }
-            }
-            _ => continue,
+        if let Message::PeerStatus { connected, limits } =
+            serde_json::from_str(&line)?
+        {
+            return Ok(Some((connected, limits)));
         }
     }
-    Err("socket closed before result".into())
+    Ok(None)
 }
 
 async fn write_json_line<W: AsyncWrite + Unpin>(
@@ -1156,6 +5147,111 @@ fn default_control_socket() -> PathBuf {
     base.join(format!("clipcast-{}.sock", user))
 }
 
+/// Where `server --daemon` listens for `server --attach` sessions: next
+/// to its control socket, so daemons with different control sockets
+/// don't meet.
+fn daemon_socket(control_socket: &Path) -> PathBuf {
+    control_socket.with_extension("daemon.sock")
+}
+
+/// Takes the daemon's lock file beside `control_socket`, held as long as
+/// the returned file stays open; `None` if another daemon holds it.
+fn lock_daemon(
+    control_socket: &Path,
+) -> Result<Option<std::fs::File>, Box<dyn std::error::Error>> {
+    let path = control_socket.with_extension("daemon.lock");
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .mode(0o600)
+        .open(&path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    // SAFETY: `flock` only takes the descriptor, which `file` owns.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) }
+        == 0
+    {
+        return Ok(Some(file));
+    }
+    let e = std::io::Error::last_os_error();
+    if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        return Ok(None);
+    }
+    Err(format!("{}: {}", path.display(), e).into())
+}
+
+fn daemon_log() -> PathBuf {
+    expand_home("~/.clipcast/daemon.log")
+}
+
+/// The socket a running `clipcast client --host <host>` listens on for
+/// `clipcast push`; one per host, so several clients can run at once.
+fn client_control_socket(host: &str) -> PathBuf {
+    let base = std::env::var("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("/tmp"));
+    let user = std::env::var("USER").unwrap_or_else(|_| "default".to_string());
+    base.join(format!("clipcast-{}-{}.sock", user, sanitize_component(host)))
+}
+
+/// A name as it travels: the text itself or, if it isn't UTF-8, an
+/// escaped form for display plus the raw bytes in base64.
+fn wire_name(name: &OsStr) -> (String, Option<String>) {
+    match name.to_str() {
+        Some(s) => (s.to_string(), None),
+        None => {
+            (escape_name(name.as_bytes()), Some(B64.encode(name.as_bytes())))
+        }
+    }
+}
+
+/// The name to save a received file or directory under: `local_name` of
+/// the raw bytes if the sender had to send them, else the name itself.
+fn receive_name(name: &str, raw_b64: Option<&str>) -> String {
+    match raw_b64.and_then(|b| B64.decode(b).ok()) {
+        Some(raw) => {
+            let local = local_name(&raw);
+            info!("non-UTF-8 name {} saved as {}", name, local);
+            local
+        }
+        None => name.to_string(),
+    }
+}
+
+/// `raw` for display: valid UTF-8 as is, other bytes as `\xNN`.
+fn escape_name(raw: &[u8]) -> String {
+    decode_name(raw, |b, out| out.push_str(&format!("\\x{:02x}", b)))
+}
+
+/// A file name for `raw`: valid UTF-8 as is, other bytes read as Latin-1,
+/// the usual legacy encoding, so `caf\xe9.txt` becomes `café.txt`.
+fn local_name(raw: &[u8]) -> String {
+    decode_name(raw, |b, out| out.push(char::from(b)))
+}
+
+/// Decodes `raw` as UTF-8, handing each byte that isn't to `bad`.
+fn decode_name(mut raw: &[u8], bad: impl Fn(u8, &mut String)) -> String {
+    let mut out = String::with_capacity(raw.len());
+    loop {
+        match std::str::from_utf8(raw) {
+            Ok(text) => {
+                out.push_str(text);
+                return out;
+            }
+            Err(e) => {
+                let (good, rest) = raw.split_at(e.valid_up_to());
+                // Valid by construction.
+                out.push_str(std::str::from_utf8(good).unwrap_or_default());
+                let n = e.error_len().unwrap_or(rest.len());
+                for b in &rest[..n] {
+                    bad(*b, &mut out);
+                }
+                raw = &rest[n..];
+            }
+        }
+    }
+}
+
 fn sanitize_basename(s: &str) -> Option<String> {
     let p = Path::new(s);
     let name = p.file_name()?.to_str()?;
@@ -1214,10 +5310,24 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     if let Some(name) = basename.as_deref() {
         if name != "clipcast" && !name.is_empty() {
-            init_tracing();
             let rest: Vec<OsString> = std::env::args_os().skip(1).collect();
-            return match run_open_client(rest).await {
-                Ok(()) => Ok(()),
+            if let Some(mode) = clip_shim_mode(name, &rest) {
+                // No tracing here: log lines on stdout would end up in the
+                // pasted text.
+                return match run_clip_shim(mode).await {
+                    Ok(()) => Ok(()),
+                    Err(e) => {
+                        eprintln!("{}: {}", name, e);
+                        std::process::exit(1);
+                    }
+                };
+            }
+            // Log to stderr: the shim mirrors the command's output on
+            // stdout.
+            init_tracing_to(std::io::stderr);
+            return match run_open_client(name, rest).await {
+                Ok(0) => Ok(()),
+                Ok(code) => std::process::exit(code),
                 Err(e) => {
                     eprintln!("{}: {}", name, e);
                     std::process::exit(1);
@@ -1230,19 +5340,47 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     match cli.command {
         Cmd::Server(server) => run_server(server).await?,
-        Cmd::Client(client) => run_client(client).await?,
+        Cmd::Client(client) => run_client(*client).await?,
         Cmd::Generate(generate) => generate_completion(generate.shell),
         Cmd::Deploy(deploy_cmd) => {
             init_tracing();
             deploy::run(deploy_cmd).await?
         }
+        Cmd::Gc(gc_cmd) => gc::run(gc_cmd)?,
+        Cmd::Push(push_cmd) => {
+            if let Err(e) = run_push(push_cmd).await {
+                eprintln!("push: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Cmd::Edit(edit_cmd) => match run_edit(edit_cmd).await {
+            Ok(0) => {}
+            Ok(code) => std::process::exit(code),
+            Err(e) => {
+                eprintln!("edit: {}", e);
+                std::process::exit(1);
+            }
+        },
     }
     Ok(())
 }
 
 async fn run_server(cli: ServerCmd) -> Result<(), Box<dyn std::error::Error>> {
+    if cli.daemon {
+        init_tracing();
+    }
     let mut server = Server::new(cli);
-    server.run().await
+    let result = server.run().await;
+    // tokio's stdin reader sits on a blocking thread that runtime shutdown
+    // waits for, so returning normally would linger until ssh closes our
+    // stdin. Exit explicitly once the socket guard has run.
+    match result {
+        Ok(()) => std::process::exit(0),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
 async fn run_client(cli: ClientCmd) -> Result<(), Box<dyn std::error::Error>> {
@@ -1277,6 +5415,13 @@ pub fn generate_completion(shell: Shell) {
 }
 
 pub fn init_tracing() {
+    init_tracing_to(std::io::stdout);
+}
+
+fn init_tracing_to<W>(writer: W)
+where
+    W: for<'w> tracing_subscriber::fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
     use std::str::FromStr;
     use tracing_subscriber::layer::SubscriberExt;
     use tracing_subscriber::util::SubscriberInitExt;
@@ -1292,7 +5437,323 @@ pub fn init_tracing() {
         .with_file(true)
         .with_line_number(false)
         .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .with_writer(writer)
         .compact();
 
     tracing_subscriber::registry().with(env_filter).with(fmt_layer).init();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_relative_path_accepts_plain_paths() {
+        assert_eq!(safe_relative_path("a.txt"), Some(PathBuf::from("a.txt")));
+        assert_eq!(
+            safe_relative_path("dir/sub/a.txt"),
+            Some(PathBuf::from("dir/sub/a.txt"))
+        );
+        // Dots inside a name are just part of it.
+        assert_eq!(
+            safe_relative_path("..a/b..c/.hidden"),
+            Some(PathBuf::from("..a/b..c/.hidden"))
+        );
+    }
+
+    #[test]
+    fn safe_relative_path_rejects_climbing() {
+        for s in ["..", "../a", "a/..", "a/../../b", ".", "./a", "a/./b"] {
+            assert_eq!(safe_relative_path(s), None, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn safe_relative_path_rejects_absolute_and_empty() {
+        for s in ["", "/", "/etc/passwd", "a//b", "a/", "//a"] {
+            assert_eq!(safe_relative_path(s), None, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn safe_relative_path_rejects_nul() {
+        assert_eq!(safe_relative_path("a\0b"), None);
+        assert_eq!(safe_relative_path("dir/\0"), None);
+    }
+
+    #[test]
+    fn safe_relative_path_limits_depth_and_length() {
+        let deep = vec!["d"; 64].join("/");
+        assert!(safe_relative_path(&deep).is_some());
+        assert_eq!(safe_relative_path(&format!("{}/d", deep)), None);
+
+        let name = "n".repeat(255);
+        assert!(safe_relative_path(&name).is_some());
+        assert_eq!(safe_relative_path(&format!("{}n", name)), None);
+
+        // 16 components of 255 bytes and their separators make 4095.
+        let fits = vec![name.as_str(); 16].join("/");
+        assert_eq!(fits.len(), 4095);
+        assert!(safe_relative_path(&fits).is_some());
+        assert_eq!(safe_relative_path(&format!("{}/ab", fits)), None);
+    }
+
+    #[test]
+    fn decode_name_passes_utf8_through() {
+        assert_eq!(escape_name("café.txt".as_bytes()), "café.txt");
+        assert_eq!(local_name("café.txt".as_bytes()), "café.txt");
+        assert_eq!(escape_name(b""), "");
+    }
+
+    #[test]
+    fn decode_name_handles_invalid_bytes_in_the_middle() {
+        assert_eq!(escape_name(b"caf\xe9.txt"), "caf\\xe9.txt");
+        assert_eq!(local_name(b"caf\xe9.txt"), "café.txt");
+        // Runs of bad bytes, with valid text between them.
+        assert_eq!(escape_name(b"\xff\xfea\x80"), "\\xff\\xfea\\x80");
+        assert_eq!(local_name(b"\xff\xfea\x80"), "ÿþa\u{80}");
+    }
+
+    #[test]
+    fn decode_name_handles_invalid_bytes_at_the_end() {
+        assert_eq!(escape_name(b"name\xff"), "name\\xff");
+        assert_eq!(local_name(b"name\xff"), "nameÿ");
+    }
+
+    #[test]
+    fn decode_name_handles_truncated_sequences() {
+        // The first two bytes of `€` (e2 82 ac).
+        assert_eq!(escape_name(b"a\xe2\x82"), "a\\xe2\\x82");
+        assert_eq!(local_name(b"a\xe2\x82"), "aâ\u{82}");
+        // Cut short by the next character rather than the end.
+        assert_eq!(escape_name(b"a\xe2\x82b"), "a\\xe2\\x82b");
+        assert_eq!(escape_name(b"\xe2\x82\xac\xe2"), "€\\xe2");
+    }
+
+    /// A Mac-side receiver with the built-in `open` and `edit` handlers,
+    /// writing below `base`.
+    fn edit_receiver(base: &Path) -> ReceiverCtx {
+        let (tx, _) = mpsc::channel(OUTBOUND_QUEUE);
+        let mut ctx = push_receiver(base, tx);
+        ctx.accept_dest = false;
+        ctx.strict_handlers = false;
+        ctx.handlers.clear();
+        for (name, allowlist) in
+            [("open", DEFAULT_OPEN_ALLOWLIST), ("edit", DEFAULT_EDIT_ALLOWLIST)]
+        {
+            let handler = OpenHandler {
+                cmd: Some("true".to_string()),
+                allowlist: parse_allowlist(allowlist),
+                save_to: None,
+                trusted: false,
+            };
+            ctx.handlers.insert(name.to_string(), Arc::new(handler));
+        }
+        ctx
+    }
+
+    fn edit_request(name: &str) -> (Vec<OpenFileMeta>, Vec<ArgSlot>) {
+        let meta = OpenFileMeta {
+            basename: name.to_string(),
+            size: 4,
+            ..Default::default()
+        };
+        (vec![meta], vec![ArgSlot::File { index: 0 }])
+    }
+
+    #[tokio::test]
+    async fn edit_needs_the_edit_handler() {
+        let base = std::env::temp_dir()
+            .join(format!("clipcast-edit-handler-{}", std::process::id()));
+        let mut ctx = edit_receiver(&base);
+        // `open` by name, and an unknown name falling back to it.
+        for name in ["open", "xdg-open"] {
+            let (files, slots) = edit_request("evil.command");
+            let begin = BeginOpts { edit: true, ..Default::default() };
+            let err = handle_open_begin(
+                &mut ctx,
+                1,
+                name,
+                files,
+                Vec::new(),
+                slots,
+                begin,
+            )
+            .await
+            .err()
+            .expect("edit through the open handler");
+            assert!(err.to_string().contains("does not handle edit"), "{err}");
+        }
+        let _ = std::fs::remove_dir_all(&base);
+    }
+
+    #[tokio::test]
+    async fn edit_session_judges_the_file() {
+        let base = std::env::temp_dir()
+            .join(format!("clipcast-edit-judge-{}", std::process::id()));
+        let mut ctx = edit_receiver(&base);
+        let (files, slots) = edit_request("evil.command");
+        let begin = BeginOpts { edit: true, ..Default::default() };
+        handle_open_begin(&mut ctx, 2, "edit", files, Vec::new(), slots, begin)
+            .await
+            .unwrap();
+        let state =
+            handle_open_chunk(&mut ctx, 2, 0, &B64.encode("true"), true)
+                .await
+                .unwrap()
+                .expect("all chunks in");
+        let (tx, _rx) = mpsc::channel(OUTBOUND_QUEUE);
+        let Message::OpenResult { ok, error, run, .. } =
+            edit_session(2, state, &tx).await
+        else {
+            panic!("not an open_result");
+        };
+        assert!(!ok);
+        assert!(run.is_none(), "the editor ran");
+        let error = error.unwrap();
+        assert!(error.contains("extension not allowed"), "{error}");
+        let _ = std::fs::remove_dir_all(&base);
+    }
+
+    #[tokio::test]
+    async fn cache_hits_fill_before_open_have() {
+        let base = std::env::temp_dir()
+            .join(format!("clipcast-cache-fill-{}", std::process::id()));
+        let mut ctx = edit_receiver(&base);
+        let (tx, mut rx) = mpsc::channel(OUTBOUND_QUEUE);
+        ctx.outbound_tx = tx;
+        let cache_dir = base.join("cache");
+        let digest = hex_digest(Sha256::new_with_prefix("data"));
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        std::fs::write(cache_dir.join(&digest), "data").unwrap();
+        ctx.cache_dir = Some(cache_dir);
+        let cached = OpenFileMeta {
+            basename: "a.txt".to_string(),
+            size: 4,
+            sha256: Some(digest),
+            ..Default::default()
+        };
+        let streamed = OpenFileMeta {
+            basename: "b.txt".to_string(),
+            size: 4,
+            ..Default::default()
+        };
+        let slots =
+            vec![ArgSlot::File { index: 0 }, ArgSlot::File { index: 1 }];
+        let begun = handle_open_begin(
+            &mut ctx,
+            5,
+            "open",
+            vec![cached, streamed],
+            Vec::new(),
+            slots,
+            BeginOpts::default(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(begun, Begun::Filling));
+        let Some(Message::OpenHave { request_id: 5, indices }) =
+            rx.recv().await
+        else {
+            panic!("no open_have");
+        };
+        assert_eq!(indices, [0]);
+        let state =
+            handle_open_chunk(&mut ctx, 5, 1, &B64.encode("more"), true)
+                .await
+                .unwrap()
+                .expect("all chunks in");
+        assert_eq!(std::fs::read_to_string(&state.paths[0]).unwrap(), "data");
+        assert_eq!(std::fs::read_to_string(&state.paths[1]).unwrap(), "more");
+        let _ = std::fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn move_into_never_overwrites() {
+        let base = std::env::temp_dir()
+            .join(format!("clipcast-move-into-{}", std::process::id()));
+        let dest = base.join("Downloads");
+        std::fs::create_dir_all(&dest).unwrap();
+        std::fs::write(dest.join("report.pdf"), "old").unwrap();
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let src = base.join(format!("req{}", i));
+                std::fs::create_dir_all(src.join("tree")).unwrap();
+                std::fs::write(src.join("report.pdf"), i.to_string()).unwrap();
+                std::fs::write(src.join("tree/a.txt"), "a").unwrap();
+                let dest = dest.clone();
+                std::thread::spawn(move || {
+                    let paths = [src.join("report.pdf"), src.join("tree")];
+                    move_into(&paths, &dest).map_err(|e| e.to_string())
+                })
+            })
+            .collect();
+        let mut moved = Vec::new();
+        for t in threads {
+            moved.extend(t.join().unwrap().unwrap());
+        }
+        let mut contents: Vec<String> = moved
+            .iter()
+            .filter(|p| p.is_file())
+            .map(|p| std::fs::read_to_string(p).unwrap())
+            .collect();
+        contents.sort();
+        assert_eq!(contents, ["0", "1", "2", "3"]);
+        assert!(moved
+            .iter()
+            .filter(|p| p.is_dir())
+            .all(|p| p.join("a.txt").is_file()));
+        assert_eq!(
+            std::fs::read_to_string(dest.join("report.pdf")).unwrap(),
+            "old"
+        );
+        assert_eq!(std::fs::read_dir(&dest).unwrap().count(), 9);
+        let _ = std::fs::remove_dir_all(&base);
+    }
+
+    #[tokio::test]
+    async fn save_to_judges_before_moving() {
+        let base = std::env::temp_dir()
+            .join(format!("clipcast-save-to-{}", std::process::id()));
+        let save_to = base.join("Downloads");
+        let mut ctx = edit_receiver(&base);
+        let handler = OpenHandler {
+            cmd: None,
+            allowlist: parse_allowlist("txt"),
+            save_to: Some(save_to.clone()),
+            trusted: false,
+        };
+        ctx.handlers.insert("dl".to_string(), Arc::new(handler));
+        let follows = Mutex::new(HashMap::new());
+        for (id, name, saved) in
+            [(3, "evil.command", false), (4, "a.txt", true)]
+        {
+            let (files, slots) = edit_request(name);
+            let begin = BeginOpts::default();
+            handle_open_begin(
+                &mut ctx,
+                id,
+                "dl",
+                files,
+                Vec::new(),
+                slots,
+                begin,
+            )
+            .await
+            .unwrap();
+            let state =
+                handle_open_chunk(&mut ctx, id, 0, &B64.encode("data"), true)
+                    .await
+                    .unwrap()
+                    .expect("all chunks in");
+            let Message::OpenResult { ok, error, .. } =
+                finalize_open(id, state, &follows).await
+            else {
+                panic!("not an open_result");
+            };
+            assert_eq!(ok, saved, "{}: {:?}", name, error);
+            assert_eq!(save_to.join(name).exists(), saved, "{}", name);
+        }
+        let _ = std::fs::remove_dir_all(&base);
+    }
+}
@@ -0,0 +1,477 @@
+//! Per-host policy for files received by `open`: which extensions are
+//! opened, which are only saved, whether the content has to match the
+//! extension, and size caps.
+//!
+//! The policy file (`clipcast client --open-policy`) is JSON with a
+//! `default` rule set and optional per-host overrides:
+//!
+//! ```json
+//! {
+//!   "default": { "open": "pdf,png,txt", "save": "zip,csv" },
+//!   "hosts": {
+//!     "ec2": { "open": "pdf", "max_file_size": "10M", "sniff": true }
+//!   }
+//! }
+//! ```
+//!
+//! A host entry overrides `default` field by field. Every decision
+//! carries the name of the rule that made it (`hosts.ec2.open`), which is
+//! what the remote caller sees when a file is refused.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::gc::{format_size, parse_size};
+use crate::OpenLimits;
+
+type BoxError = Box<dyn std::error::Error>;
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    default: RuleSet,
+    #[serde(default)]
+    hosts: HashMap<String, RuleSet>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+struct RuleSet {
+    /// Extensions that may be opened. Unset = the handler's allowlist.
+    open: Option<String>,
+    /// Extensions that are saved but never opened.
+    save: Option<String>,
+    /// Whether the content must agree with the extension.
+    sniff: Option<bool>,
+    max_file_size: Option<String>,
+    max_total_size: Option<String>,
+}
+
+/// A resolved setting and the rule it came from.
+#[derive(Debug, Clone)]
+struct Rule<T> {
+    value: T,
+    origin: String,
+}
+
+/// The rules in force for one host.
+#[derive(Debug)]
+pub struct OpenPolicy {
+    open: Option<Rule<HashSet<String>>>,
+    save: Rule<HashSet<String>>,
+    sniff: Rule<bool>,
+    max_file_size: Rule<u64>,
+    max_total_size: Rule<u64>,
+    /// The client's configured limits, which no rule can raise.
+    ceiling: OpenLimits,
+}
+
+/// What to do with one file or directory argument.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Open,
+    /// Keep the file but leave it out of the command.
+    SaveOnly,
+    /// Fail the request; the string names the rule.
+    Refuse(String),
+}
+
+impl OpenPolicy {
+    /// The policy without a policy file: open what the handler allows,
+    /// sniff content, and the configured size limits.
+    pub fn builtin(limits: OpenLimits) -> Self {
+        OpenPolicy {
+            open: None,
+            save: Rule { value: HashSet::new(), origin: "built-in".into() },
+            sniff: Rule { value: true, origin: "built-in sniff".into() },
+            max_file_size: Rule {
+                value: limits.max_file_size,
+                origin: "max_file_size limit".into(),
+            },
+            max_total_size: Rule {
+                value: limits.max_total_size,
+                origin: "max_total_size limit".into(),
+            },
+            ceiling: limits,
+        }
+    }
+
+    /// Reads the policy file and resolves the rules for `host`.
+    pub fn load(
+        path: &Path,
+        host: &str,
+        limits: OpenLimits,
+    ) -> Result<Self, BoxError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let file: PolicyFile = serde_json::from_str(&text)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut policy = OpenPolicy::builtin(limits);
+        policy.apply(&file.default, "default")?;
+        if let Some(rules) = file.hosts.get(host) {
+            policy.apply(rules, &format!("hosts.{}", host))?;
+        }
+        Ok(policy)
+    }
+
+    fn apply(&mut self, rules: &RuleSet, scope: &str) -> Result<(), BoxError> {
+        let origin = |field: &str| format!("{}.{}", scope, field);
+        if let Some(open) = &rules.open {
+            self.open =
+                Some(Rule { value: parse_exts(open), origin: origin("open") });
+        }
+        if let Some(save) = &rules.save {
+            self.save =
+                Rule { value: parse_exts(save), origin: origin("save") };
+        }
+        if let Some(sniff) = rules.sniff {
+            self.sniff = Rule { value: sniff, origin: origin("sniff") };
+        }
+        // A policy can only tighten the configured limits.
+        if let Some(s) = &rules.max_file_size {
+            let n = parse_size(s).map_err(|e| {
+                format!("{}: {:?}: {}", origin("max_file_size"), s, e)
+            })?;
+            self.max_file_size = Rule {
+                value: n.min(self.ceiling.max_file_size),
+                origin: origin("max_file_size"),
+            };
+        }
+        if let Some(s) = &rules.max_total_size {
+            let n = parse_size(s).map_err(|e| {
+                format!("{}: {:?}: {}", origin("max_total_size"), s, e)
+            })?;
+            self.max_total_size = Rule {
+                value: n.min(self.ceiling.max_total_size),
+                origin: origin("max_total_size"),
+            };
+        }
+        Ok(())
+    }
+
+    /// The limits in force, as advertised to the sender.
+    pub fn limits(&self) -> OpenLimits {
+        OpenLimits {
+            max_file_size: self.max_file_size.value,
+            max_total_size: self.max_total_size.value,
+            max_files: self.ceiling.max_files,
+        }
+    }
+
+    /// Checks announced sizes before anything is written.
+    pub fn check_sizes<'a>(
+        &self,
+        files: impl Iterator<Item = (&'a str, u64)>,
+    ) -> Result<(), String> {
+        let mut total: u64 = 0;
+        for (name, size) in files {
+            if size > self.max_file_size.value {
+                return Err(format!(
+                    "{}: {} exceeds {} (rule: {})",
+                    name,
+                    format_size(size),
+                    format_size(self.max_file_size.value),
+                    self.max_file_size.origin
+                ));
+            }
+            total = total.saturating_add(size);
+        }
+        if total > self.max_total_size.value {
+            return Err(format!(
+                "request total {} exceeds {} (rule: {})",
+                format_size(total),
+                format_size(self.max_total_size.value),
+                self.max_total_size.origin
+            ));
+        }
+        Ok(())
+    }
+
+    /// Decides by name what happens to a received file or directory.
+    /// `allowlist` is the handler's, used when the policy sets no `open`
+    /// list. A directory without an extension opens as a folder; one with
+    /// an extension (`Foo.app`) is judged like a file, since `open` would
+    /// launch it as a bundle.
+    pub fn judge_name(
+        &self,
+        name: &str,
+        is_dir: bool,
+        allowlist: &HashSet<String>,
+    ) -> Verdict {
+        let ext = match Path::new(name).extension().and_then(|s| s.to_str()) {
+            Some(ext) => ext.to_lowercase(),
+            None if is_dir => return Verdict::Open,
+            None => String::new(),
+        };
+        let (open, origin) = match &self.open {
+            Some(rule) => (&rule.value, rule.origin.as_str()),
+            None => (allowlist, "handler allowlist"),
+        };
+        if !ext.is_empty() && open.contains(&ext) {
+            Verdict::Open
+        } else if !ext.is_empty() && self.save.value.contains(&ext) {
+            Verdict::SaveOnly
+        } else {
+            Verdict::Refuse(format!(
+                "{}: extension not allowed (rule: {})",
+                name, origin
+            ))
+        }
+    }
+
+    /// Checks that a file's content agrees with its extension. Native
+    /// executables are refused whatever they are called.
+    pub fn check_content(&self, name: &str, path: &Path) -> Result<(), String> {
+        if !self.sniff.value {
+            return Ok(());
+        }
+        let head = read_head(path)
+            .map_err(|e| format!("{}: reading for sniffing: {}", name, e))?;
+        let ext = Path::new(name)
+            .extension()
+            .and_then(|s| s.to_str())
+            .map(|s| s.to_lowercase())
+            .unwrap_or_default();
+        content_agrees(&ext, &head).map_err(|why| {
+            format!("{}: {} (rule: {})", name, why, self.sniff.origin)
+        })
+    }
+}
+
+fn parse_exts(csv: &str) -> HashSet<String> {
+    csv.split(',')
+        .map(|s| s.trim().trim_start_matches('.').to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn read_head(path: &Path) -> std::io::Result<Vec<u8>> {
+    use std::io::Read;
+    let mut head = Vec::with_capacity(512);
+    std::fs::File::open(path)?.take(512).read_to_end(&mut head)?;
+    Ok(head)
+}
+
+/// Extensions whose files always start with a recognisable signature,
+/// and the `magic_kind` each must show.
+const MAGIC_EXTS: &[(&str, &str)] = &[
+    ("pdf", "pdf"),
+    ("png", "png"),
+    ("jpg", "jpg"),
+    ("jpeg", "jpg"),
+    ("gif", "gif"),
+    ("webp", "webp"),
+    ("zip", "zip"),
+    ("docx", "zip"),
+    ("xlsx", "zip"),
+    ("pptx", "zip"),
+    ("epub", "zip"),
+    ("mp3", "mp3"),
+    ("wav", "wav"),
+    ("mp4", "mp4"),
+    ("m4a", "mp4"),
+    ("mov", "mp4"),
+];
+
+/// Extensions opened as text, which must not hold a binary format.
+const TEXT_EXTS: &[&str] =
+    &["txt", "md", "csv", "json", "log", "html", "htm", "svg", "xml"];
+
+fn content_agrees(ext: &str, head: &[u8]) -> Result<(), String> {
+    if let Some(kind) = executable_kind(head) {
+        return Err(format!("content is an executable ({})", kind));
+    }
+    let kind = magic_kind(head);
+    if let Some((_, want)) = MAGIC_EXTS.iter().find(|(e, _)| *e == ext) {
+        return match kind {
+            Some(k) if k == *want => Ok(()),
+            Some(k) => Err(format!("content is {}, not {}", k, ext)),
+            None => Err(format!("content is not a {} file", ext)),
+        };
+    }
+    match kind {
+        Some(k) if TEXT_EXTS.contains(&ext) => {
+            Err(format!("content is {}, not text", k))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn executable_kind(head: &[u8]) -> Option<&'static str> {
+    const MAGIC: &[(&[u8], &str)] = &[
+        (b"\x7fELF", "ELF"),
+        (b"\xcf\xfa\xed\xfe", "Mach-O"),
+        (b"\xce\xfa\xed\xfe", "Mach-O"),
+        (b"\xfe\xed\xfa\xcf", "Mach-O"),
+        (b"\xfe\xed\xfa\xce", "Mach-O"),
+        (b"\xca\xfe\xba\xbe", "Mach-O universal"),
+    ];
+    if let Some((_, kind)) = MAGIC.iter().find(|(m, _)| head.starts_with(m)) {
+        return Some(kind);
+    }
+    // A DOS header is full of zero bytes; text that happens to start with
+    // "MZ" has none.
+    if head.starts_with(b"MZ") && head.len() >= 64 && head[..64].contains(&0) {
+        return Some("Windows");
+    }
+    None
+}
+
+/// Binary formats recognised by their leading bytes, named by their usual
+/// extension.
+fn magic_kind(data: &[u8]) -> Option<&'static str> {
+    const MAGIC: &[(&[u8], &str)] = &[
+        (b"%PDF-", "pdf"),
+        (b"\x89PNG\r\n\x1a\n", "png"),
+        (b"\xff\xd8\xff", "jpg"),
+        (b"GIF87a", "gif"),
+        (b"GIF89a", "gif"),
+        (b"PK\x03\x04", "zip"),
+        (b"PK\x05\x06", "zip"),
+        (b"ID3", "mp3"),
+    ];
+    if let Some((_, ext)) = MAGIC.iter().find(|(m, _)| data.starts_with(m)) {
+        return Some(ext);
+    }
+    if data.len() >= 12 && &data[..4] == b"RIFF" {
+        match &data[8..12] {
+            b"WEBP" => return Some("webp"),
+            b"WAVE" => return Some("wav"),
+            _ => {}
+        }
+    }
+    if data.len() >= 12 && &data[4..8] == b"ftyp" {
+        return Some("mp4");
+    }
+    if is_mp3_frame(data) {
+        return Some("mp3");
+    }
+    None
+}
+
+/// Whether `data` starts with an MPEG audio layer III frame header (an
+/// `mp3` without an ID3 tag). Byte order marks never qualify.
+fn is_mp3_frame(data: &[u8]) -> bool {
+    let [a, b, c, ..] = *data else { return false };
+    if data.starts_with(b"\xff\xfe") || data.starts_with(b"\xfe\xff") {
+        return false;
+    }
+    let sync = a == 0xff && b & 0xe0 == 0xe0;
+    let version = (b >> 3) & 0b11;
+    let layer = (b >> 1) & 0b11;
+    let bitrate = c >> 4;
+    let rate = (c >> 2) & 0b11;
+    // Version 01 and sample rate 11 are reserved, bitrate 1111 is invalid.
+    sync && version != 0b01 && layer == 0b01 && bitrate != 0xf && rate != 0b11
+}
+
+/// Guesses a file extension from leading magic bytes or, for text, the
+/// first markup. `None` if nothing matches.
+pub fn sniff_extension(data: &[u8]) -> Option<&'static str> {
+    if let Some(kind) = magic_kind(data) {
+        if kind == "mp4" && data.get(8..10) == Some(b"qt") {
+            return Some("mov");
+        }
+        return Some(kind);
+    }
+    let head = String::from_utf8_lossy(&data[..data.len().min(512)])
+        .trim_start()
+        .to_ascii_lowercase();
+    if head.starts_with("<!doctype html") || head.starts_with("<html") {
+        Some("html")
+    } else if head.starts_with("<svg")
+        || (head.starts_with("<?xml") && head.contains("<svg"))
+    {
+        Some("svg")
+    } else if (head.starts_with('{') || head.starts_with('['))
+        && serde_json::from_slice::<serde::de::IgnoredAny>(data).is_ok()
+    {
+        Some("json")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn magic_kind_recognises_signatures() {
+        assert_eq!(magic_kind(b"%PDF-1.7\n"), Some("pdf"));
+        assert_eq!(magic_kind(b"PK\x03\x04rest"), Some("zip"));
+        assert_eq!(magic_kind(b"RIFF\0\0\0\0WEBPVP8 "), Some("webp"));
+        assert_eq!(magic_kind(b"\0\0\0\x18ftypmp42"), Some("mp4"));
+        assert_eq!(magic_kind(b"ID3\x04\0"), Some("mp3"));
+        assert_eq!(magic_kind(b"plain text"), None);
+        assert_eq!(magic_kind(b""), None);
+    }
+
+    #[test]
+    fn magic_kind_needs_a_valid_mp3_frame_header() {
+        // MPEG-1 layer III, 128 kbit/s, 44.1 kHz.
+        assert_eq!(magic_kind(b"\xff\xfb\x90\x64"), Some("mp3"));
+        // Layer I, reserved version, bad bitrate, reserved sample rate.
+        assert_eq!(magic_kind(b"\xff\xff\x90\x64"), None);
+        assert_eq!(magic_kind(b"\xff\xeb\x90\x64"), None);
+        assert_eq!(magic_kind(b"\xff\xfb\xf0\x64"), None);
+        assert_eq!(magic_kind(b"\xff\xfb\x9c\x64"), None);
+        assert_eq!(magic_kind(b"\xff\xfb"), None);
+    }
+
+    #[test]
+    fn magic_kind_ignores_byte_order_marks() {
+        let utf16le: Vec<u8> = "\u{feff}notes"
+            .encode_utf16()
+            .flat_map(|u| u.to_le_bytes())
+            .collect();
+        assert_eq!(magic_kind(&utf16le), None);
+        assert_eq!(magic_kind(b"\xfe\xff\0n\0o"), None);
+        assert_eq!(magic_kind(b"\xef\xbb\xbfnotes"), None);
+    }
+
+    #[test]
+    fn content_agrees_with_text() {
+        let utf16le: Vec<u8> = "\u{feff}hello"
+            .encode_utf16()
+            .flat_map(|u| u.to_le_bytes())
+            .collect();
+        assert_eq!(content_agrees("txt", &utf16le), Ok(()));
+        assert_eq!(content_agrees("txt", b"MZ notes about the build"), Ok(()));
+        assert_eq!(content_agrees("md", b"# title"), Ok(()));
+        assert!(content_agrees("txt", b"%PDF-1.4").is_err());
+    }
+
+    #[test]
+    fn content_agrees_refuses_executables() {
+        let mut pe = b"MZ\x90\0\x03\0\0\0".to_vec();
+        pe.resize(128, 0);
+        assert_eq!(
+            content_agrees("pdf", &pe),
+            Err("content is an executable (Windows)".to_string())
+        );
+        assert!(content_agrees("txt", b"\x7fELF\x02\x01").is_err());
+        assert!(content_agrees("bin", b"\xcf\xfa\xed\xfe").is_err());
+    }
+
+    #[test]
+    fn content_agrees_checks_signature_extensions() {
+        assert_eq!(content_agrees("pdf", b"%PDF-1.7"), Ok(()));
+        assert_eq!(content_agrees("docx", b"PK\x03\x04"), Ok(()));
+        assert_eq!(
+            content_agrees("pdf", b"PK\x03\x04"),
+            Err("content is zip, not pdf".to_string())
+        );
+        assert_eq!(
+            content_agrees("png", b"hello"),
+            Err("content is not a png file".to_string())
+        );
+        assert_eq!(content_agrees("mp3", b"\xff\xfb\x90\x64"), Ok(()));
+        assert!(content_agrees("mp3", b"\xff\xfeh\0").is_err());
+        // Unknown extensions only get the executable check.
+        assert_eq!(content_agrees("dat", b"\x00\x01\x02"), Ok(()));
+    }
+}